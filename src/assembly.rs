@@ -0,0 +1,530 @@
+// Just enough of ECMA-335 to pull the TargetFrameworkAttribute out of an assembly
+
+const TYPE_REF: usize = 0x01;
+const MEMBER_REF: usize = 0x0A;
+const CUSTOM_ATTRIBUTE: usize = 0x0C;
+
+const RESOLUTION_SCOPE: &[usize] = &[0x00, 0x1A, 0x23, 0x01];
+const TYPE_DEF_OR_REF: &[usize] = &[0x02, 0x01, 0x1B];
+const MEMBER_REF_PARENT: &[usize] = &[0x02, 0x01, 0x1A, 0x06, 0x1B];
+const HAS_CONSTANT: &[usize] = &[0x04, 0x08, 0x17];
+const HAS_CUSTOM_ATTRIBUTE: &[usize] = &[
+    0x06, 0x04, 0x01, 0x02, 0x08, 0x09, 0x0A, 0x00, 0x0E, 0x17, 0x14, 0x11, 0x1A, 0x1B, 0x20, 0x23,
+    0x26, 0x27, 0x28, 0x2A, 0x2C, 0x2B,
+];
+const CUSTOM_ATTRIBUTE_TYPE: &[usize] = &[0x06, 0x0A];
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn compressed_u32(data: &[u8], offset: usize) -> Option<(u32, usize)> {
+    let first = *data.get(offset)? as u32;
+    if first & 0x80 == 0 {
+        Some((first, 1))
+    } else if first & 0xC0 == 0x80 {
+        Some((((first & 0x3F) << 8) | *data.get(offset + 1)? as u32, 2))
+    } else if first & 0xE0 == 0xC0 {
+        let rest = data.get(offset + 1..offset + 4)?;
+        Some((
+            ((first & 0x1F) << 24)
+                | (rest[0] as u32) << 16
+                | (rest[1] as u32) << 8
+                | rest[2] as u32,
+            4,
+        ))
+    } else {
+        None
+    }
+}
+
+// Returns the CLI metadata blob of a PE image
+fn cli_metadata(image: &[u8]) -> Option<&[u8]> {
+    if image.get(0..2)? != b"MZ" {
+        return None;
+    }
+    let pe = u32_at(image, 0x3C)? as usize;
+    if image.get(pe..pe + 4)? != b"PE\0\0" {
+        return None;
+    }
+
+    let coff = pe + 4;
+    let section_count = u16_at(image, coff + 2)? as usize;
+    let optional = coff + 20;
+    let section_table = optional + u16_at(image, coff + 16)? as usize;
+
+    let (directory_count, directories) = match u16_at(image, optional)? {
+        0x10B => (u32_at(image, optional + 92)?, optional + 96),
+        0x20B => (u32_at(image, optional + 108)?, optional + 112),
+        _ => return None,
+    };
+    if directory_count <= 14 {
+        return None;
+    }
+
+    let resolve = |rva: u32| -> Option<usize> {
+        (0..section_count).find_map(|i| {
+            let section = section_table + i * 40;
+            let size = u32_at(image, section + 8)?.max(u32_at(image, section + 16)?);
+            let address = u32_at(image, section + 12)?;
+            let raw = u32_at(image, section + 20)?;
+            (rva >= address && rva - address < size)
+                .then(|| (rva - address).checked_add(raw))
+                .flatten()
+                .map(|offset| offset as usize)
+        })
+    };
+
+    let cli = resolve(u32_at(image, directories + 14 * 8)?)?;
+    let metadata = resolve(u32_at(image, cli + 8)?)?;
+    image.get(metadata..metadata + u32_at(image, cli + 12)? as usize)
+}
+
+pub struct MetadataRoot<'a> {
+    pub version: &'a str,
+    streams: Vec<(&'a [u8], &'a [u8])>,
+}
+
+impl<'a> MetadataRoot<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if u32_at(data, 0)? != 0x424A_5342 {
+            return None;
+        }
+        let length = u32_at(data, 12)? as usize;
+        let version = std::str::from_utf8(data.get(16..16 + length)?)
+            .ok()?
+            .trim_end_matches('\0');
+
+        let mut pos = 16 + length.next_multiple_of(4);
+        let count = u16_at(data, pos + 2)?;
+        pos += 4;
+
+        let mut streams = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let offset = u32_at(data, pos)? as usize;
+            let size = u32_at(data, pos + 4)? as usize;
+            let name_start = pos + 8;
+            let name_len = data.get(name_start..)?.iter().position(|&b| b == 0)?;
+            streams.push((
+                &data[name_start..name_start + name_len],
+                data.get(offset..offset + size)?,
+            ));
+            pos = name_start + (name_len + 1).next_multiple_of(4);
+        }
+
+        Some(Self { version, streams })
+    }
+
+    pub fn stream(&self, name: &str) -> Option<&'a [u8]> {
+        self.streams
+            .iter()
+            .find(|(n, _)| *n == name.as_bytes())
+            .map(|(_, data)| *data)
+    }
+
+    fn target_framework_name(&self) -> Option<&'a str> {
+        let tables = Tables::parse(self.stream("#~").or_else(|| self.stream("#-"))?)?;
+        let strings = self.stream("#Strings")?;
+        let blobs = self.stream("#Blob")?;
+
+        let string = |index: u32| -> Option<&'a str> {
+            let data = strings.get(index as usize..)?;
+            std::str::from_utf8(&data[..data.iter().position(|&b| b == 0)?]).ok()
+        };
+        let blob = |index: u32| -> Option<&'a [u8]> {
+            let (len, size) = compressed_u32(blobs, index as usize)?;
+            let start = index as usize + size;
+            blobs.get(start..start + len as usize)
+        };
+
+        for row in 0..tables.rows[CUSTOM_ATTRIBUTE] {
+            let mut pos = tables.row(CUSTOM_ATTRIBUTE, row)?;
+            pos += tables.coded_size(HAS_CUSTOM_ATTRIBUTE, 5);
+            let attr_type = tables.read(pos, tables.coded_size(CUSTOM_ATTRIBUTE_TYPE, 3))?;
+            pos += tables.coded_size(CUSTOM_ATTRIBUTE_TYPE, 3);
+            let value = tables.read(pos, tables.blob_size)?;
+
+            // MemberRef constructor on a TypeRef
+            if attr_type & 0x7 != 3 || attr_type >> 3 == 0 {
+                continue;
+            }
+            let member = tables.row(MEMBER_REF, (attr_type >> 3) - 1)?;
+            let class = tables.read(member, tables.coded_size(MEMBER_REF_PARENT, 3))?;
+            if class & 0x7 != 1 || class >> 3 == 0 {
+                continue;
+            }
+            let type_ref = tables.row(TYPE_REF, (class >> 3) - 1)?;
+            let name_pos = type_ref + tables.coded_size(RESOLUTION_SCOPE, 2);
+            let name = string(tables.read(name_pos, tables.string_size)?)?;
+            let namespace =
+                string(tables.read(name_pos + tables.string_size, tables.string_size)?)?;
+            if name != "TargetFrameworkAttribute" || namespace != "System.Runtime.Versioning" {
+                continue;
+            }
+
+            let value = blob(value)?;
+            if value.get(0..2)? != [1, 0] {
+                return None;
+            }
+            let (len, size) = compressed_u32(value, 2)?;
+            return std::str::from_utf8(value.get(2 + size..2 + size + len as usize)?).ok();
+        }
+
+        None
+    }
+}
+
+struct Tables<'a> {
+    data: &'a [u8],
+    rows: [u32; 64],
+    offsets: [usize; CUSTOM_ATTRIBUTE + 1],
+    sizes: [usize; CUSTOM_ATTRIBUTE + 1],
+    string_size: usize,
+    guid_size: usize,
+    blob_size: usize,
+}
+
+impl<'a> Tables<'a> {
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        let heap_sizes = *data.get(6)?;
+        let valid = u64_at(data, 8)?;
+
+        let mut rows = [0; 64];
+        let mut pos = 24;
+        for (table, count) in rows.iter_mut().enumerate() {
+            if valid & (1 << table) != 0 {
+                *count = u32_at(data, pos)?;
+                pos += 4;
+            }
+        }
+        if heap_sizes & 0x40 != 0 {
+            pos += 4;
+        }
+
+        let mut tables = Self {
+            data,
+            rows,
+            offsets: [0; CUSTOM_ATTRIBUTE + 1],
+            sizes: [0; CUSTOM_ATTRIBUTE + 1],
+            string_size: if heap_sizes & 0x1 != 0 { 4 } else { 2 },
+            guid_size: if heap_sizes & 0x2 != 0 { 4 } else { 2 },
+            blob_size: if heap_sizes & 0x4 != 0 { 4 } else { 2 },
+        };
+
+        let (s, g, b) = (tables.string_size, tables.guid_size, tables.blob_size);
+        tables.sizes = [
+            2 + s + 3 * g,
+            tables.coded_size(RESOLUTION_SCOPE, 2) + 2 * s,
+            4 + 2 * s
+                + tables.coded_size(TYPE_DEF_OR_REF, 2)
+                + tables.index_size(0x04)
+                + tables.index_size(0x06),
+            tables.index_size(0x04),
+            2 + s + b,
+            tables.index_size(0x06),
+            8 + s + b + tables.index_size(0x08),
+            tables.index_size(0x08),
+            4 + s,
+            tables.index_size(0x02) + tables.coded_size(TYPE_DEF_OR_REF, 2),
+            tables.coded_size(MEMBER_REF_PARENT, 3) + s + b,
+            2 + tables.coded_size(HAS_CONSTANT, 2) + b,
+            tables.coded_size(HAS_CUSTOM_ATTRIBUTE, 5)
+                + tables.coded_size(CUSTOM_ATTRIBUTE_TYPE, 3)
+                + b,
+        ];
+        for table in 0..=CUSTOM_ATTRIBUTE {
+            tables.offsets[table] = pos;
+            pos += tables.sizes[table] * tables.rows[table] as usize;
+        }
+
+        Some(tables)
+    }
+
+    fn index_size(&self, table: usize) -> usize {
+        if self.rows[table] < 1 << 16 {
+            2
+        } else {
+            4
+        }
+    }
+
+    fn coded_size(&self, tables: &[usize], tag_bits: u32) -> usize {
+        let max = tables.iter().map(|&t| self.rows[t]).max().unwrap_or(0);
+        if max < 1 << (16 - tag_bits) {
+            2
+        } else {
+            4
+        }
+    }
+
+    fn row(&self, table: usize, row: u32) -> Option<usize> {
+        (row < self.rows[table]).then(|| self.offsets[table] + self.sizes[table] * row as usize)
+    }
+
+    fn read(&self, pos: usize, size: usize) -> Option<u32> {
+        match size {
+            2 => u16_at(self.data, pos).map(u32::from),
+            _ => u32_at(self.data, pos),
+        }
+    }
+}
+
+// Maps a FrameworkName such as `.NETFramework,Version=v4.7.2` to a NuGet TFM
+fn tfm_from_framework_name(name: &str) -> Option<String> {
+    let mut parts = name.split(',');
+    let identifier = parts.next()?.trim();
+    let version = parts
+        .find_map(|part| part.trim().strip_prefix("Version="))?
+        .trim_start_matches('v');
+
+    let numbers = version
+        .split('.')
+        .map(|n| n.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let major = *numbers.first()?;
+    let minor = numbers.get(1).copied().unwrap_or(0);
+
+    match identifier {
+        ".NETFramework" => Some(format!(
+            "net{}",
+            numbers.iter().map(|n| n.to_string()).collect::<String>()
+        )),
+        ".NETStandard" => Some(format!("netstandard{major}.{minor}")),
+        ".NETCoreApp" if major >= 5 => Some(format!("net{major}.{minor}")),
+        ".NETCoreApp" => Some(format!("netcoreapp{major}.{minor}")),
+        _ => None,
+    }
+}
+
+// Assemblies without the attribute predate .NET 4, so the runtime version is the best hint
+pub fn target_framework(image: &[u8]) -> Option<String> {
+    let root = MetadataRoot::parse(cli_metadata(image)?)?;
+
+    match root
+        .target_framework_name()
+        .and_then(tfm_from_framework_name)
+    {
+        Some(tfm) => Some(tfm),
+        None if root.version.starts_with("v1.") || root.version.starts_with("v2.") => {
+            Some("net35".to_string())
+        }
+        None => None,
+    }
+}
+
+// The SSQP key id of a portable PDB, its GUID in `N` format followed by `FFFFFFFF`
+pub fn portable_pdb_id(pdb: &[u8]) -> Option<String> {
+    let id = MetadataRoot::parse(pdb)?.stream("#Pdb")?.get(0..16)?;
+
+    Some(format!(
+        "{:08x}{:04x}{:04x}{}ffffffff",
+        u32_at(id, 0)?,
+        u16_at(id, 4)?,
+        u16_at(id, 6)?,
+        id[8..]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TFM: &str = ".NETStandard,Version=v2.0";
+    const PE: usize = 0x80;
+    const OPTIONAL: usize = PE + 24;
+    const CLI_DIRECTORY: usize = OPTIONAL + 96 + 14 * 8;
+    const SECTIONS: usize = OPTIONAL + 224;
+    const ADDRESS: u32 = 0x2000;
+
+    // A metadata root holding the given streams, each padded to 4 bytes
+    fn metadata(version: &str, streams: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut version = version.as_bytes().to_vec();
+        version.resize((version.len() + 1).next_multiple_of(4), 0);
+        let headers: usize = streams
+            .iter()
+            .map(|(name, _)| 8 + (name.len() + 1).next_multiple_of(4))
+            .sum();
+
+        let mut data = vec![];
+        data.extend(0x424A_5342u32.to_le_bytes());
+        data.extend([1, 0, 1, 0, 0, 0, 0, 0]);
+        data.extend((version.len() as u32).to_le_bytes());
+        data.extend(&version);
+        data.extend([0, 0]);
+        data.extend((streams.len() as u16).to_le_bytes());
+
+        let mut offset = data.len() + headers;
+        for (name, stream) in streams {
+            data.extend((offset as u32).to_le_bytes());
+            data.extend((stream.len() as u32).to_le_bytes());
+            let mut name = name.as_bytes().to_vec();
+            name.resize((name.len() + 1).next_multiple_of(4), 0);
+            data.extend(name);
+            offset += stream.len().next_multiple_of(4);
+        }
+        for (_, stream) in streams {
+            data.extend(stream);
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+        data
+    }
+
+    // TypeRef, MemberRef and CustomAttribute tables with one row each, for a `[TargetFramework]`
+    fn target_framework_metadata(framework_name: &str) -> Vec<u8> {
+        let strings = b"\0TargetFrameworkAttribute\0System.Runtime.Versioning\0".to_vec();
+        let mut value = vec![1, 0, framework_name.len() as u8];
+        value.extend(framework_name.as_bytes());
+        value.extend([0, 0]);
+        let mut blobs = vec![0, value.len() as u8];
+        blobs.extend(value);
+
+        let mut tables = vec![0, 0, 0, 0, 2, 0, 0, 1];
+        let valid = (1u64 << TYPE_REF) | (1 << MEMBER_REF) | (1 << CUSTOM_ATTRIBUTE);
+        tables.extend(valid.to_le_bytes());
+        tables.extend(0u64.to_le_bytes());
+        tables.extend([1u32, 1, 1].iter().flat_map(|x| x.to_le_bytes()));
+        for row in [
+            // Scope, name, namespace
+            [0u16, 1, 26],
+            // TypeRef 1 as the class, name and signature
+            [(1 << 3) | 1, 0, 0],
+            // Parent, MemberRef 1 as the constructor, value
+            [0, (1 << 3) | 3, 1],
+        ] {
+            tables.extend(row.iter().flat_map(|x| x.to_le_bytes()));
+        }
+
+        metadata(
+            "v4.0.30319",
+            &[("#~", tables), ("#Strings", strings), ("#Blob", blobs)],
+        )
+    }
+
+    // A PE32 image with one section holding the CLI header followed by the metadata
+    fn image(metadata: &[u8], raw: u32) -> Vec<u8> {
+        let put = |image: &mut Vec<u8>, offset: usize, bytes: &[u8]| {
+            image[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+
+        let mut image = vec![0; 0x200];
+        put(&mut image, 0, b"MZ");
+        put(&mut image, 0x3C, &(PE as u32).to_le_bytes());
+        put(&mut image, PE, b"PE\0\0");
+        put(&mut image, PE + 6, &1u16.to_le_bytes());
+        put(&mut image, PE + 20, &224u16.to_le_bytes());
+        put(&mut image, OPTIONAL, &0x10Bu16.to_le_bytes());
+        put(&mut image, OPTIONAL + 92, &16u32.to_le_bytes());
+        put(&mut image, CLI_DIRECTORY, &ADDRESS.to_le_bytes());
+        put(&mut image, CLI_DIRECTORY + 4, &72u32.to_le_bytes());
+
+        let size = 72 + metadata.len() as u32;
+        put(&mut image, SECTIONS + 8, &size.to_le_bytes());
+        put(&mut image, SECTIONS + 12, &ADDRESS.to_le_bytes());
+        put(&mut image, SECTIONS + 16, &size.to_le_bytes());
+        put(&mut image, SECTIONS + 20, &raw.to_le_bytes());
+
+        let mut cli = vec![0; 72];
+        put(&mut cli, 0, &72u32.to_le_bytes());
+        put(&mut cli, 8, &(ADDRESS + 72).to_le_bytes());
+        put(&mut cli, 12, &(metadata.len() as u32).to_le_bytes());
+        image.extend(cli);
+        image.extend(metadata);
+        image
+    }
+
+    #[test]
+    fn target_framework_is_read_from_the_attribute() {
+        let dll = image(&target_framework_metadata(TFM), 0x200);
+        assert_eq!(target_framework(&dll).as_deref(), Some("netstandard2.0"));
+
+        let dll = image(
+            &target_framework_metadata(".NETFramework,Version=v4.7.2"),
+            0x200,
+        );
+        assert_eq!(target_framework(&dll).as_deref(), Some("net472"));
+    }
+
+    #[test]
+    fn old_runtimes_fall_back_to_net35() {
+        let dll = image(&metadata("v2.0.50727", &[]), 0x200);
+        assert_eq!(target_framework(&dll).as_deref(), Some("net35"));
+
+        let dll = image(&metadata("v4.0.30319", &[]), 0x200);
+        assert_eq!(target_framework(&dll), None);
+    }
+
+    #[test]
+    fn framework_names_map_to_tfms() {
+        for (name, tfm) in [
+            (".NETFramework,Version=v4.7.2", Some("net472")),
+            (".NETFramework,Version=v3.5", Some("net35")),
+            (".NETStandard,Version=v2.1", Some("netstandard2.1")),
+            (".NETCoreApp,Version=v3.1", Some("netcoreapp3.1")),
+            (".NETCoreApp,Version=v8.0", Some("net8.0")),
+            (".NETCoreApp, Version=v6.0, Profile=Client", Some("net6.0")),
+            (".NETPortable,Version=v4.5", None),
+            (".NETFramework", None),
+            (".NETFramework,Version=vX", None),
+        ] {
+            assert_eq!(tfm_from_framework_name(name).as_deref(), tfm, "{name}");
+        }
+    }
+
+    #[test]
+    fn truncated_images_are_rejected() {
+        let dll = image(&target_framework_metadata(TFM), 0x200);
+        for len in 0..dll.len() {
+            assert_eq!(target_framework(&dll[..len]), None, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn malformed_images_dont_panic() {
+        let dll = image(&target_framework_metadata(TFM), 0x200);
+        for i in 0..dll.len() {
+            for byte in [0x00, 0x7F, 0xFF] {
+                let mut dll = dll.clone();
+                dll[i] = byte;
+                target_framework(&dll);
+            }
+        }
+
+        // The section's file offset overflows once the rva's offset into it is added
+        let mut dll = image(&target_framework_metadata(TFM), u32::MAX);
+        dll[CLI_DIRECTORY..CLI_DIRECTORY + 4].copy_from_slice(&(ADDRESS + 8).to_le_bytes());
+        assert_eq!(target_framework(&dll), None);
+        assert_eq!(target_framework(b"MZ"), None);
+        assert_eq!(target_framework(b"not an assembly"), None);
+    }
+
+    #[test]
+    fn portable_pdb_id_is_the_guid_and_ffffffff() {
+        let mut pdb_stream: Vec<u8> = (1..=16).collect();
+        pdb_stream.extend([0; 4]);
+        let pdb = metadata("PDB v1.0", &[("#Pdb", pdb_stream)]);
+        assert_eq!(
+            portable_pdb_id(&pdb).as_deref(),
+            Some("0403020106050807090a0b0c0d0e0f10ffffffff")
+        );
+
+        assert_eq!(portable_pdb_id(&pdb[..pdb.len() - 8]), None);
+        assert_eq!(portable_pdb_id(&metadata("PDB v1.0", &[])), None);
+    }
+}
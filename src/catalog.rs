@@ -0,0 +1,524 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::metadata::{NugetDependencyGroup, Snapshot, TSPackage, TSVersion};
+use crate::version::Version;
+
+pub const CATALOG_DIR: &str = "catalog";
+const PAGE_SIZE: usize = 550;
+
+#[derive(Error, Debug)]
+pub enum CatalogError {
+    #[error("Failed to access catalog; {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Catalog is malformed; {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CatalogKind {
+    PackageDetails,
+    PackageDelete,
+}
+
+// What a catalog page lists about each leaf
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CatalogItem {
+    pub commit_id: String,
+    pub commit_timestamp: String,
+    pub kind: CatalogKind,
+    pub id: String,
+    pub version: String,
+}
+
+// The parts of a Thunderstore version that are worth telling mirrors about
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct CatalogDetails {
+    pub description: String,
+    pub icon_url: String,
+    pub project_url: String,
+    pub package_url: String,
+    pub published: String,
+    pub deprecated: bool,
+    pub dependencies: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+impl CatalogDetails {
+    fn new(pkg: &TSPackage, version: &TSVersion) -> Self {
+        Self {
+            description: version.description.clone(),
+            icon_url: version.icon.clone(),
+            project_url: version.website_url.clone(),
+            package_url: pkg.package_url.clone(),
+            published: version.date_created.clone(),
+            deprecated: pkg.is_deprecated,
+            dependencies: version.dependencies.clone(),
+            tags: pkg.categories.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StoredLeaf {
+    item: CatalogItem,
+    details: Option<CatalogDetails>,
+}
+
+// An append only log of package changes, split in pages of PAGE_SIZE leaves
+#[derive(Clone)]
+pub struct Catalog {
+    dir: PathBuf,
+    base_url: String,
+    pages: Vec<Arc<Vec<CatalogItem>>>,
+}
+
+impl Catalog {
+    pub fn load(dir: impl AsRef<Path>, base_url: &str) -> Result<Self, CatalogError> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(dir.join("pages"))?;
+        std::fs::create_dir_all(dir.join("data"))?;
+
+        let mut pages = vec![];
+        loop {
+            let path = page_path(&dir, pages.len());
+            let file = match std::fs::File::open(&path) {
+                Ok(file) => file,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => break,
+                Err(err) => return Err(err.into()),
+            };
+            pages.push(Arc::new(serde_json::from_reader(BufReader::new(file))?));
+        }
+
+        Ok(Self {
+            dir,
+            base_url: base_url.to_string(),
+            pages,
+        })
+    }
+
+    // Records what changed between two snapshots as a single commit, returning the number of leaves
+    pub fn commit(&mut self, previous: &Snapshot, next: &Snapshot) -> Result<usize, CatalogError> {
+        let before = versions(previous);
+        let after = versions(next);
+
+        let commit_id = uuid::Uuid::new_v4().to_string();
+        let commit_timestamp = timestamp(SystemTime::now());
+        let item = |kind, id: &str, version: &str| CatalogItem {
+            commit_id: commit_id.clone(),
+            commit_timestamp: commit_timestamp.clone(),
+            kind,
+            id: id.to_string(),
+            version: version.to_string(),
+        };
+
+        let mut leaves = vec![];
+        for (key, (pkg, version, normalized)) in &after {
+            let details = CatalogDetails::new(pkg, version);
+            let unchanged = before
+                .get(key)
+                .is_some_and(|(pkg, version, _)| CatalogDetails::new(pkg, version) == details);
+            if !unchanged {
+                leaves.push(StoredLeaf {
+                    item: item(CatalogKind::PackageDetails, &pkg.full_name, normalized),
+                    details: Some(details),
+                });
+            }
+        }
+        for (key, (pkg, _, normalized)) in &before {
+            if !after.contains_key(key) {
+                leaves.push(StoredLeaf {
+                    item: item(CatalogKind::PackageDelete, &pkg.full_name, normalized),
+                    details: None,
+                });
+            }
+        }
+
+        if leaves.is_empty() {
+            return Ok(0);
+        }
+        leaves.sort_unstable_by(|a, b| {
+            (a.item.id.to_lowercase(), &a.item.version)
+                .cmp(&(b.item.id.to_lowercase(), &b.item.version))
+        });
+
+        // Leaves go first so a page never points at something that isn't on disk
+        let commit_dir = self.dir.join("data").join(&commit_id);
+        let result = self.write(&commit_dir, leaves);
+        if result.is_err() {
+            // Nothing refers to this commit yet, so a retry can start over
+            let _ = std::fs::remove_dir_all(&commit_dir);
+        }
+        result
+    }
+
+    // Either every touched page is replaced or, on error, none of them are
+    fn write(&mut self, commit_dir: &Path, leaves: Vec<StoredLeaf>) -> Result<usize, CatalogError> {
+        std::fs::create_dir_all(commit_dir)?;
+        for leaf in &leaves {
+            let path = commit_dir.join(leaf_name(&leaf.item.id, &leaf.item.version));
+            let mut file = BufWriter::new(std::fs::File::create(path)?);
+            serde_json::to_writer(&mut file, leaf)?;
+            file.flush()?;
+        }
+
+        let count = leaves.len();
+        let mut pages = self.pages.clone();
+        let mut touched = vec![];
+        for leaf in leaves {
+            match pages.last_mut() {
+                Some(page) if page.len() < PAGE_SIZE => Arc::make_mut(page).push(leaf.item),
+                _ => pages.push(Arc::new(vec![leaf.item])),
+            }
+            if touched.last() != Some(&(pages.len() - 1)) {
+                touched.push(pages.len() - 1);
+            }
+        }
+
+        let mut written = vec![];
+        for &page in &touched {
+            match write_page(&self.dir, page, &pages[page]) {
+                Ok(temp_path) => written.push((temp_path, page_path(&self.dir, page))),
+                Err(err) => {
+                    for (temp_path, _) in written {
+                        let _ = std::fs::remove_file(temp_path);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        for (temp_path, path) in written {
+            std::fs::rename(temp_path, path)?;
+        }
+
+        self.pages = pages;
+        Ok(count)
+    }
+
+    pub fn index(&self) -> Value {
+        let base_url = &self.base_url;
+        let items: Vec<_> = self
+            .pages
+            .iter()
+            .enumerate()
+            .map(|(number, page)| {
+                let last = page.last().unwrap();
+                json!({
+                    "@id": format!("{base_url}/nuget/v3/catalog/page/{number}.json"),
+                    "@type": "CatalogPage",
+                    "commitId": last.commit_id,
+                    "commitTimeStamp": last.commit_timestamp,
+                    "count": page.len(),
+                })
+            })
+            .collect();
+        let last = self.pages.last().and_then(|page| page.last());
+
+        json!({
+            "@id": format!("{base_url}/nuget/v3/catalog/index.json"),
+            "@type": ["CatalogRoot", "AppendOnlyCatalog", "Permalink"],
+            "commitId": last.map(|x| &x.commit_id),
+            "commitTimeStamp": last.map_or(timestamp(UNIX_EPOCH), |x| x.commit_timestamp.clone()),
+            "count": items.len(),
+            "items": items,
+        })
+    }
+
+    pub fn page(&self, number: usize) -> Option<Value> {
+        let base_url = &self.base_url;
+        let page = self.pages.get(number)?;
+        let last = page.last()?;
+
+        let items: Vec<_> = page
+            .iter()
+            .map(|item| {
+                json!({
+                    "@id": self.leaf_url(item),
+                    "@type": match item.kind {
+                        CatalogKind::PackageDetails => "nuget:PackageDetails",
+                        CatalogKind::PackageDelete => "nuget:PackageDelete",
+                    },
+                    "commitId": item.commit_id,
+                    "commitTimeStamp": item.commit_timestamp,
+                    "nuget:id": item.id,
+                    "nuget:version": item.version,
+                })
+            })
+            .collect();
+
+        Some(json!({
+            "@id": format!("{base_url}/nuget/v3/catalog/page/{number}.json"),
+            "@type": "CatalogPage",
+            "commitId": last.commit_id,
+            "commitTimeStamp": last.commit_timestamp,
+            "count": items.len(),
+            "parent": format!("{base_url}/nuget/v3/catalog/index.json"),
+            "items": items,
+        }))
+    }
+
+    fn leaf_url(&self, item: &CatalogItem) -> String {
+        format!(
+            "{}/nuget/v3/catalog/data/{}/{}",
+            self.base_url,
+            item.commit_id,
+            leaf_name(&item.id, &item.version)
+        )
+    }
+
+    // `name` is the `{id}.{version}.json` part of a leaf url
+    pub async fn leaf(&self, commit_id: &str, name: &str) -> Option<Value> {
+        let valid = |x: &str| {
+            !x.is_empty()
+                && x.bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || b"-_.+".contains(&c))
+                && !x.starts_with('.')
+        };
+        if !valid(commit_id) || !valid(name) {
+            return None;
+        }
+
+        let path = self.dir.join("data").join(commit_id).join(name);
+        let bytes = tokio::fs::read(path).await.ok()?;
+        let StoredLeaf { item, details } = serde_json::from_slice(&bytes).ok()?;
+        let url = self.leaf_url(&item);
+
+        Some(match details {
+            Some(details) => json!({
+                "@id": url,
+                "@type": ["PackageDetails", "catalog:Permalink"],
+                "catalog:commitId": item.commit_id,
+                "catalog:commitTimeStamp": item.commit_timestamp,
+                "id": item.id,
+                "version": item.version,
+                "authors": item.id.split_once('-').map_or("", |(owner, _)| owner),
+                "description": details.description,
+                "iconUrl": details.icon_url,
+                "projectUrl": details.project_url,
+                "packageUrl": details.package_url,
+                "published": details.published,
+                "listed": true,
+                "tags": details.tags,
+                "deprecated": details.deprecated,
+                "dependencyGroups": [NugetDependencyGroup::new(
+                    &url,
+                    None,
+                    &details.dependencies,
+                    &self.base_url,
+                )],
+            }),
+            None => json!({
+                "@id": url,
+                "@type": ["PackageDelete", "catalog:Permalink"],
+                "catalog:commitId": item.commit_id,
+                "catalog:commitTimeStamp": item.commit_timestamp,
+                "id": item.id,
+                "originalId": item.id,
+                "version": item.version,
+                "published": item.commit_timestamp,
+            }),
+        })
+    }
+}
+
+// Every valid version in a snapshot with its normalized form, by lowercased id and version
+fn versions(snapshot: &Snapshot) -> HashMap<(String, String), (&TSPackage, &TSVersion, String)> {
+    let mut versions = HashMap::new();
+    for pkg in snapshot.communities.values().flat_map(|x| x.iter()) {
+        for version in &pkg.versions {
+            let Ok(parsed) = version.version_number.parse::<Version>() else {
+                continue;
+            };
+            let normalized = parsed.to_string();
+            versions
+                .entry((pkg.full_name.to_lowercase(), normalized.to_lowercase()))
+                .or_insert((pkg, version, normalized));
+        }
+    }
+    versions
+}
+
+fn page_path(dir: &Path, page: usize) -> PathBuf {
+    dir.join("pages").join(format!("{page}.json"))
+}
+
+// Written next to the real page, returning the temp file to rename over it
+fn write_page(dir: &Path, page: usize, items: &[CatalogItem]) -> Result<PathBuf, CatalogError> {
+    let temp_path = page_path(dir, page).with_extension("tmp");
+
+    let mut file = BufWriter::new(std::fs::File::create(&temp_path)?);
+    serde_json::to_writer(&mut file, items)?;
+    file.flush()?;
+
+    Ok(temp_path)
+}
+
+fn leaf_name(id: &str, version: &str) -> String {
+    format!("{}.{}.json", id.to_lowercase(), version.to_lowercase())
+}
+
+// ISO 8601 in UTC with the 7 fractional digits NuGet uses, so cursors compare as strings
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Days to a civil date, from Howard Hinnant's algorithm
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:07}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_nanos() / 100
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn snapshot(versions: impl IntoIterator<Item = (String, String)>) -> Snapshot {
+        let mut packages: Vec<TSPackage> = vec![];
+        for (full_name, version_number) in versions {
+            let version = TSVersion {
+                description: format!("{full_name} {version_number}"),
+                icon: String::new(),
+                version_number,
+                download_url: String::new(),
+                downloads: 0,
+                date_created: "2024-01-01T00:00:00Z".to_string(),
+                website_url: String::new(),
+                dependencies: vec![],
+            };
+            match packages.iter_mut().find(|x| x.full_name == full_name) {
+                Some(pkg) => pkg.versions.push(version),
+                None => packages.push(TSPackage {
+                    full_name,
+                    uuid4: String::new(),
+                    package_url: String::new(),
+                    is_deprecated: false,
+                    categories: vec![],
+                    versions: vec![version],
+                }),
+            }
+        }
+
+        let mut snapshot = Snapshot::default();
+        snapshot
+            .communities
+            .insert("test".to_string(), Arc::new(packages));
+        snapshot
+    }
+
+    fn mod_versions(count: usize) -> Vec<(String, String)> {
+        (0..count)
+            .map(|i| ("Author-Mod".to_string(), format!("1.0.{i}")))
+            .collect()
+    }
+
+    #[test]
+    fn timestamps_are_iso_8601_with_7_digits() {
+        let at = |secs, nanos| timestamp(UNIX_EPOCH + Duration::new(secs, nanos));
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.0000000Z");
+        assert_eq!(at(951_782_400, 123_456_789), "2000-02-29T00:00:00.1234567Z");
+        assert_eq!(
+            at(1_709_251_199, 999_999_999),
+            "2024-02-29T23:59:59.9999999Z"
+        );
+        assert_eq!(at(1_735_689_600, 0), "2025-01-01T00:00:00.0000000Z");
+        assert_eq!(at(4_107_542_400, 100), "2100-03-01T00:00:00.0000001Z");
+        // Before the epoch clamps instead of going negative
+        assert_eq!(
+            timestamp(UNIX_EPOCH - Duration::from_secs(1)),
+            "1970-01-01T00:00:00.0000000Z"
+        );
+        assert!(at(1_709_251_199, 0) < at(1_709_251_200, 0));
+    }
+
+    #[test]
+    fn leaves_fill_pages_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut catalog = Catalog::load(dir.path(), "http://localhost").unwrap();
+        let empty = Snapshot::default();
+        let first = snapshot(mod_versions(PAGE_SIZE + 10));
+        assert_eq!(catalog.commit(&empty, &first).unwrap(), PAGE_SIZE + 10);
+        assert_eq!(catalog.commit(&first, &first).unwrap(), 0);
+
+        let index = catalog.index();
+        assert_eq!(index["count"], 2);
+        assert_eq!(index["items"][0]["count"], PAGE_SIZE);
+        assert_eq!(index["items"][1]["count"], 10);
+        assert_eq!(
+            index["items"][1]["@id"],
+            "http://localhost/nuget/v3/catalog/page/1.json"
+        );
+
+        // One version added and one removed land on the last page, which still has room
+        let mut versions = mod_versions(PAGE_SIZE + 10);
+        versions.remove(0);
+        versions.push(("Author-Other".to_string(), "2.0".to_string()));
+        let second = snapshot(versions);
+        assert_eq!(catalog.commit(&first, &second).unwrap(), 2);
+        assert!(catalog.page(2).is_none());
+
+        let page = catalog.page(1).unwrap();
+        assert_eq!(page["count"], 12);
+        let items = page["items"].as_array().unwrap();
+        assert_eq!(items[10]["@type"], "nuget:PackageDelete");
+        assert_eq!(items[10]["nuget:version"], "1.0.0");
+        assert_eq!(items[11]["@type"], "nuget:PackageDetails");
+        assert_eq!(items[11]["nuget:version"], "2.0.0");
+        assert_eq!(page["commitId"], items[11]["commitId"]);
+        assert!(items[11]["commitTimeStamp"].as_str() > items[9]["commitTimeStamp"].as_str());
+
+        // Pages survive a restart
+        let reloaded = Catalog::load(dir.path(), "http://localhost").unwrap();
+        assert_eq!(reloaded.index(), catalog.index());
+        assert_eq!(reloaded.page(1), catalog.page(1));
+    }
+
+    #[tokio::test]
+    async fn leaves_are_read_back_from_their_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut catalog = Catalog::load(dir.path(), "http://localhost").unwrap();
+        let next = snapshot(mod_versions(1));
+        catalog.commit(&Snapshot::default(), &next).unwrap();
+
+        let item = &catalog.page(0).unwrap()["items"][0];
+        let url = item["@id"].as_str().unwrap();
+        let (commit_id, name) = url
+            .strip_prefix("http://localhost/nuget/v3/catalog/data/")
+            .and_then(|x| x.split_once('/'))
+            .unwrap();
+        assert_eq!(name, "author-mod.1.0.0.json");
+
+        let leaf = catalog.leaf(commit_id, name).await.unwrap();
+        assert_eq!(leaf["@id"], url);
+        assert_eq!(leaf["id"], "Author-Mod");
+        assert_eq!(leaf["description"], "Author-Mod 1.0.0");
+        assert_eq!(leaf["catalog:commitTimeStamp"], item["commitTimeStamp"]);
+
+        assert!(catalog
+            .leaf(commit_id, "../../pages/0.json")
+            .await
+            .is_none());
+        assert!(catalog.leaf("..", name).await.is_none());
+    }
+}
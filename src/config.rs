@@ -0,0 +1,231 @@
+use clap::parser::ValueSource;
+use clap::{ArgMatches, FromArgMatches, Parser};
+use serde::Deserialize;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::layout::{Layout, LayoutError};
+use crate::nupkg::ConvertOptions;
+use crate::upstream::{Thunderstore, DEFAULT_UPSTREAM};
+use crate::{ServerConfig, DEFAULT_CACHE};
+
+/// Read when no config file is given, if it exists.
+pub const DEFAULT_CONFIG_FILE: &str = "ts-nuget.toml";
+pub const DEFAULT_PORT: u16 = 5000;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Couldn't read config file {path}; {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Config file {path} is invalid; {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error(
+        "No base url configured; set `base_url` in the config file, NUGET_BASE_URL or --base-url"
+    )]
+    MissingBaseUrl,
+    #[error("{name} `{url}` should be an http or https url")]
+    InvalidUrl { name: &'static str, url: String },
+    #[error("Refresh interval must be at least one second")]
+    ZeroRefreshInterval,
+    #[error("Community names can't be empty")]
+    EmptyCommunity,
+    #[error("Default framework can't be empty")]
+    EmptyFramework,
+    #[error("Invalid layout; {0}")]
+    Layout(#[from] LayoutError),
+}
+
+/// Serves Thunderstore packages as a NuGet v3 feed.
+///
+/// Settings come from the config file, then environment variables, then flags, each overriding the last.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    /// TOML config file [default: ts-nuget.toml, if it exists]
+    #[arg(long, env = "NUGET_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Public url of the feed, used in every document it serves
+    #[arg(long, env = "NUGET_BASE_URL")]
+    pub base_url: Option<String>,
+    /// Address to listen on [default: 0.0.0.0:5000]
+    #[arg(long, env = "NUGET_BIND")]
+    pub bind: Option<SocketAddr>,
+    /// Port to listen on, replacing the port of the bind address. NUGET_PORT leaves --bind alone
+    #[arg(long, env = "NUGET_PORT")]
+    pub port: Option<u16>,
+    /// Where the snapshot, catalog, converted packages and symbols are kept [default: .]
+    #[arg(long, env = "NUGET_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// Seconds between package list refreshes [default: 300]
+    #[arg(long, env = "NUGET_REFRESH_INTERVAL", value_name = "SECONDS")]
+    pub refresh_interval: Option<u64>,
+    /// Thunderstore instance to serve packages from [default: https://thunderstore.io]
+    #[arg(long, env = "NUGET_UPSTREAM_URL")]
+    pub upstream: Option<String>,
+    /// Comma separated communities to serve [default: all of them]
+    #[arg(long, env = "NUGET_COMMUNITIES", value_delimiter = ',')]
+    pub communities: Option<Vec<String>>,
+    /// Rules placing archive files in the nupkg, like `**/patchers/**=skip;**/*.dll=lib`
+    #[arg(long, env = "NUGET_LAYOUT")]
+    pub layout: Option<String>,
+    /// Framework for assemblies whose target can't be read [default: netstandard2.0]
+    #[arg(long, env = "NUGET_DEFAULT_FRAMEWORK")]
+    pub default_framework: Option<String>,
+    /// Bytes of converted packages to keep on disk, evicting the least recently used [default: unbounded]
+    #[arg(long, env = "NUGET_NUPKG_CACHE_SIZE", value_name = "BYTES")]
+    pub nupkg_cache_size: Option<u64>,
+    // Set by `from_matches`, so a port from the environment doesn't replace a bind flag's
+    #[arg(skip)]
+    port_from_env: bool,
+    #[arg(skip)]
+    bind_from_flag: bool,
+}
+
+impl Args {
+    /// Reads the arguments out of `matches`, noting which came from flags rather than the environment.
+    ///
+    /// Use this rather than `Args::parse`, which can't tell them apart.
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let mut args = Self::from_arg_matches(matches)?;
+        args.port_from_env = matches.value_source("port") == Some(ValueSource::EnvVariable);
+        args.bind_from_flag = matches.value_source("bind") == Some(ValueSource::CommandLine);
+        Ok(args)
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    base_url: Option<String>,
+    bind: Option<SocketAddr>,
+    data_dir: Option<PathBuf>,
+    refresh_interval: Option<u64>,
+    upstream: Option<String>,
+    communities: Option<Vec<String>>,
+    nupkg_cache_size: Option<u64>,
+    conversion: ConversionConfig,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConversionConfig {
+    layout: Option<String>,
+    default_framework: Option<String>,
+}
+
+impl FileConfig {
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+/// A validated configuration for the server binary.
+pub struct Settings {
+    pub bind: SocketAddr,
+    pub server: ServerConfig,
+}
+
+impl Settings {
+    /// Layers `args` over the config file they point at, or `ts-nuget.toml` if there is one.
+    pub fn load(args: Args) -> Result<Self, ConfigError> {
+        let file = match &args.config {
+            Some(path) => FileConfig::read(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                FileConfig::read(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => FileConfig::default(),
+        };
+
+        let base_url = args
+            .base_url
+            .or(file.base_url)
+            .ok_or(ConfigError::MissingBaseUrl)?;
+        check_url("Base url", &base_url)?;
+        let upstream = args
+            .upstream
+            .or(file.upstream)
+            .unwrap_or_else(|| DEFAULT_UPSTREAM.to_string());
+        check_url("Upstream", &upstream)?;
+
+        let mut bind = args
+            .bind
+            .or(file.bind)
+            .unwrap_or((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT).into());
+        // Like any other setting, a port from the environment gives way to flags
+        if let Some(port) = args.port {
+            if !(args.port_from_env && args.bind_from_flag) {
+                bind.set_port(port);
+            }
+        }
+
+        let refresh_interval = match args.refresh_interval.or(file.refresh_interval) {
+            Some(0) => return Err(ConfigError::ZeroRefreshInterval),
+            Some(secs) => Duration::from_secs(secs),
+            None => DEFAULT_CACHE,
+        };
+
+        let communities = match args.communities.or(file.communities) {
+            Some(communities) => {
+                let communities: Vec<_> =
+                    communities.iter().map(|x| x.trim().to_string()).collect();
+                if communities.iter().any(|x| x.is_empty()) {
+                    return Err(ConfigError::EmptyCommunity);
+                }
+                Some(communities.into_iter().collect())
+            }
+            None => None,
+        };
+
+        let mut conversion = ConvertOptions::default();
+        if let Some(layout) = args.layout.or(file.conversion.layout) {
+            conversion.layout = Layout::parse(&layout)?;
+        }
+        if let Some(framework) = args.default_framework.or(file.conversion.default_framework) {
+            if framework.trim().is_empty() {
+                return Err(ConfigError::EmptyFramework);
+            }
+            conversion.default_framework = framework.trim().to_string();
+        }
+
+        let mut server = ServerConfig::new(&base_url);
+        if let Some(data_dir) = args.data_dir.or(file.data_dir) {
+            server.data_dir = data_dir;
+        }
+        server.upstream = Arc::new(Thunderstore::new(&upstream));
+        server.refresh_interval = refresh_interval;
+        server.communities = communities;
+        server.conversion = conversion;
+        server.nupkg_cache_size = args.nupkg_cache_size.or(file.nupkg_cache_size);
+
+        Ok(Self { bind, server })
+    }
+}
+
+fn check_url(name: &'static str, url: &str) -> Result<(), ConfigError> {
+    let rest = url
+        .strip_prefix("http://")
+        .or_else(|| url.strip_prefix("https://"));
+    match rest {
+        Some(host) if !host.is_empty() => Ok(()),
+        _ => Err(ConfigError::InvalidUrl {
+            name,
+            url: url.to_string(),
+        }),
+    }
+}
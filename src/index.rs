@@ -0,0 +1,310 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Field {
+    Id,
+    Owner,
+    Name,
+    Description,
+    Tags,
+}
+
+const FIELDS: [Field; 5] = [
+    Field::Id,
+    Field::Owner,
+    Field::Name,
+    Field::Description,
+    Field::Tags,
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Scope {
+    Any,
+    Field(Field),
+    // `packageid:` has to match the whole id
+    ExactId,
+}
+
+#[derive(Debug)]
+pub struct Term {
+    pub scope: Scope,
+    pub value: String,
+}
+
+impl Term {
+    // Splits a NuGet style query like `owner:evaisa "hook gen" tags:libraries`
+    pub fn parse(query: &str) -> Vec<Term> {
+        let mut terms = vec![];
+        let mut chars = query.chars().peekable();
+
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.peek().is_none() {
+                return terms;
+            }
+
+            let mut word = String::new();
+            let mut value = None;
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                if c == ':' && value.is_none() {
+                    value = Some(String::new());
+                } else if c == '"' {
+                    let target = value.as_mut().unwrap_or(&mut word);
+                    target.extend(chars.by_ref().take_while(|&c| c != '"'));
+                } else {
+                    value.as_mut().unwrap_or(&mut word).push(c);
+                }
+            }
+
+            let scope = match value.as_ref().map(|_| word.to_lowercase()).as_deref() {
+                None => Scope::Any,
+                Some("id") => Scope::Field(Field::Id),
+                Some("packageid") => Scope::ExactId,
+                Some("owner" | "author" | "authors") => Scope::Field(Field::Owner),
+                Some("title" | "name") => Scope::Field(Field::Name),
+                Some("description" | "summary") => Scope::Field(Field::Description),
+                Some("tags" | "tag") => Scope::Field(Field::Tags),
+                // Unknown fields are treated as plain text, like NuGet does
+                Some(_) => {
+                    terms.push(Term {
+                        scope: Scope::Any,
+                        value: format!("{word}:{}", value.unwrap()),
+                    });
+                    continue;
+                }
+            };
+
+            let value = match value {
+                Some(value) => value,
+                None => word,
+            };
+            if !value.is_empty() {
+                terms.push(Term { scope, value });
+            }
+        }
+    }
+}
+
+// Lowercased words, plus the parts of camel cased ones so `LethalLib` is found by `lib`
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+    {
+        tokens.push(word.to_lowercase());
+
+        let mut start = 0;
+        let mut prev: Option<char> = None;
+        for (i, c) in word.char_indices() {
+            if let Some(prev) = prev {
+                if (c.is_uppercase() && prev.is_lowercase()) || c.is_numeric() != prev.is_numeric()
+                {
+                    tokens.push(word[start..i].to_lowercase());
+                    start = i;
+                }
+            }
+            prev = Some(c);
+        }
+        if start > 0 {
+            tokens.push(word[start..].to_lowercase());
+        }
+    }
+
+    tokens
+}
+
+#[derive(Default)]
+pub struct SearchIndex {
+    // Lowercased full names, indexed by document
+    names: Vec<String>,
+    ids: HashMap<String, u32>,
+    fields: [BTreeMap<String, Vec<u32>>; FIELDS.len()],
+}
+
+impl SearchIndex {
+    pub fn add(&mut self, full_name: &str, description: &str, categories: &[String]) {
+        let doc = self.names.len() as u32;
+        let (owner, name) = full_name.split_once('-').unwrap_or(("", full_name));
+
+        for field in FIELDS {
+            let tokens: HashSet<String> = match field {
+                Field::Id => tokenize(full_name),
+                Field::Owner => tokenize(owner),
+                Field::Name => tokenize(name),
+                Field::Description => tokenize(description),
+                Field::Tags => categories.iter().flat_map(|x| tokenize(x)).collect(),
+            }
+            .into_iter()
+            .collect();
+
+            for token in tokens {
+                self.fields[field as usize]
+                    .entry(token)
+                    .or_default()
+                    .push(doc);
+            }
+        }
+
+        self.names.push(full_name.to_lowercase());
+        self.ids.insert(full_name.to_lowercase(), doc);
+    }
+
+    // Lowercased full names of the packages matching every term, None if nothing was searched for
+    pub fn matches(&self, terms: &[Term]) -> Option<HashSet<&str>> {
+        let mut result: Option<HashSet<u32>> = None;
+
+        for term in terms {
+            let docs = match term.scope {
+                Scope::ExactId => self
+                    .ids
+                    .get(&term.value.to_lowercase())
+                    .copied()
+                    .into_iter()
+                    .collect::<HashSet<_>>()
+                    .into(),
+                Scope::Field(field) => self.term_docs(&term.value, &[field]),
+                Scope::Any => self.term_docs(&term.value, &FIELDS),
+            };
+            // Terms without any searchable characters don't narrow anything down
+            let Some(docs) = docs else {
+                continue;
+            };
+
+            result = Some(match result {
+                Some(result) => result.intersection(&docs).copied().collect(),
+                None => docs,
+            });
+        }
+
+        result.map(|docs| {
+            docs.into_iter()
+                .map(|doc| self.names[doc as usize].as_str())
+                .collect()
+        })
+    }
+
+    // Every token of the term has to prefix a token in one of the fields
+    fn term_docs(&self, value: &str, fields: &[Field]) -> Option<HashSet<u32>> {
+        let mut result: Option<HashSet<u32>> = None;
+
+        for token in value
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_lowercase())
+        {
+            let docs: HashSet<u32> = fields
+                .iter()
+                .flat_map(|&field| {
+                    self.fields[field as usize]
+                        .range(token.clone()..)
+                        .take_while(|(key, _)| key.starts_with(&token))
+                        .flat_map(|(_, docs)| docs.iter().copied())
+                })
+                .collect();
+
+            result = Some(match result {
+                Some(result) => result.intersection(&docs).copied().collect(),
+                None => docs,
+            });
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> Vec<(Scope, String)> {
+        Term::parse(query)
+            .into_iter()
+            .map(|term| (term.scope, term.value))
+            .collect()
+    }
+
+    #[test]
+    fn queries_are_split_into_scoped_terms() {
+        assert_eq!(
+            parse(r#"  owner:evaisa "hook gen" Tags:libraries packageid:Author-Mod "#),
+            [
+                (Scope::Field(Field::Owner), "evaisa".to_string()),
+                (Scope::Any, "hook gen".to_string()),
+                (Scope::Field(Field::Tags), "libraries".to_string()),
+                (Scope::ExactId, "Author-Mod".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse(r#"description:"more than one word" title:x"#),
+            [
+                (
+                    Scope::Field(Field::Description),
+                    "more than one word".to_string()
+                ),
+                (Scope::Field(Field::Name), "x".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn odd_queries_still_parse() {
+        assert!(parse("").is_empty());
+        assert!(parse("   ").is_empty());
+        // Empty values and unknown fields
+        assert!(parse("owner:").is_empty());
+        assert_eq!(
+            parse("version:1.0 a:b:c"),
+            [
+                (Scope::Any, "version:1.0".to_string()),
+                (Scope::Any, "a:b:c".to_string()),
+            ]
+        );
+        // An unclosed quote runs to the end of the query
+        assert_eq!(
+            parse(r#"id:"lethal lib"#),
+            [(Scope::Field(Field::Id), "lethal lib".to_string())]
+        );
+    }
+
+    #[test]
+    fn camel_case_and_numbers_are_split() {
+        assert_eq!(tokenize("LethalLib"), ["lethallib", "lethal", "lib"]);
+        assert_eq!(
+            tokenize("Mod2Go v1"),
+            ["mod2go", "mod", "2", "go", "v1", "v", "1"]
+        );
+        assert_eq!(tokenize("HTTPClient"), ["httpclient"]);
+        assert_eq!(tokenize("author-some_mod!"), ["author", "some", "mod"]);
+        assert!(tokenize(" - ").is_empty());
+    }
+
+    #[test]
+    fn every_term_has_to_match() {
+        let mut index = SearchIndex::default();
+        index.add(
+            "Evaisa-LethalLib",
+            "A library for modders",
+            &["Libraries".to_string()],
+        );
+        index.add("Author-CoolMod", "Uses LethalLib", &["Mods".to_string()]);
+
+        let matches = |query: &str| {
+            let mut names: Vec<_> = index
+                .matches(&Term::parse(query))
+                .map(|x| x.into_iter().collect())
+                .unwrap_or_default();
+            names.sort_unstable();
+            names
+        };
+
+        assert_eq!(matches("lib"), ["author-coolmod", "evaisa-lethallib"]);
+        assert_eq!(matches("lib owner:evai"), ["evaisa-lethallib"]);
+        assert_eq!(matches("tags:mods"), ["author-coolmod"]);
+        assert_eq!(matches("packageid:author-coolmod"), ["author-coolmod"]);
+        assert!(matches("packageid:author-cool").is_empty());
+        assert!(matches("lib tags:nothing").is_empty());
+        assert!(index.matches(&Term::parse("!!")).is_none());
+    }
+}
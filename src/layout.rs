@@ -0,0 +1,196 @@
+use thiserror::Error;
+
+// Rules are checked in order, the first matching pattern decides where a file goes
+const DEFAULT_LAYOUT: &str =
+    "**/patchers/**=skip;**/BepInEx/core/**=skip;**/plugins/**=lib;**/*.dll=lib";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// `lib/<tfm>/`, only taking assemblies and their companions.
+    Lib,
+    Folder(String),
+    Skip,
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: Vec<String>,
+    target: Target,
+}
+
+#[derive(Debug)]
+pub struct Layout {
+    rules: Vec<Rule>,
+}
+
+#[derive(Error, Debug)]
+pub enum LayoutError {
+    #[error("Layout rule `{0}` should look like `pattern=target`")]
+    MissingTarget(String),
+    #[error("Layout rule `{0}` has an empty pattern")]
+    EmptyPattern(String),
+    #[error("Layout target `{0}` is not a relative folder")]
+    InvalidTarget(String),
+}
+
+impl Layout {
+    pub fn parse(spec: &str) -> Result<Self, LayoutError> {
+        let mut rules = vec![];
+
+        for rule in spec.split(';').map(str::trim).filter(|x| !x.is_empty()) {
+            let (pattern, target) = rule
+                .split_once('=')
+                .ok_or_else(|| LayoutError::MissingTarget(rule.to_string()))?;
+            let pattern: Vec<String> = pattern
+                .trim()
+                .split('/')
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_string())
+                .collect();
+            if pattern.is_empty() {
+                return Err(LayoutError::EmptyPattern(rule.to_string()));
+            }
+
+            let target = match target.trim().trim_matches('/') {
+                "skip" | "" => Target::Skip,
+                "lib" => Target::Lib,
+                folder if folder.split('/').any(|x| x.is_empty() || x == "..") => {
+                    return Err(LayoutError::InvalidTarget(folder.to_string()))
+                }
+                folder => Target::Folder(folder.to_string()),
+            };
+
+            rules.push(Rule { pattern, target });
+        }
+
+        Ok(Self { rules })
+    }
+
+    /// Returns where the file should go and the part of its path that's kept.
+    pub fn map<'a>(&self, path: &'a str) -> Option<(&Target, &'a str)> {
+        let segments: Vec<&str> = path.split('/').collect();
+        self.rules.iter().find_map(|rule| {
+            let start = glob(&rule.pattern, &segments, 0)?.unwrap_or(segments.len() - 1);
+            let offset: usize = segments[..start].iter().map(|x| x.len() + 1).sum();
+            Some((&rule.target, &path[offset..]))
+        })
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::parse(DEFAULT_LAYOUT).unwrap()
+    }
+}
+
+// Matches path segments against a pattern, returning where the last `**` started matching
+fn glob(pattern: &[String], path: &[&str], offset: usize) -> Option<Option<usize>> {
+    match pattern.split_first() {
+        None => path.is_empty().then_some(None),
+        Some((first, rest)) if first == "**" => (0..=path.len()).find_map(|skip| {
+            glob(rest, &path[skip..], offset + skip).map(|inner| inner.or(Some(offset)))
+        }),
+        Some((first, rest)) => {
+            let (segment, tail) = path.split_first()?;
+            if wildcard(first.as_bytes(), segment.as_bytes()) {
+                glob(rest, tail, offset + 1)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+fn wildcard(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| wildcard(rest, &text[skip..])),
+        Some((p, rest)) => match text.split_first() {
+            Some((t, tail)) => p.eq_ignore_ascii_case(t) && wildcard(rest, tail),
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(layout: &Layout, path: &'static str) -> Option<(Target, &'static str)> {
+        layout
+            .map(path)
+            .map(|(target, kept)| (target.clone(), kept))
+    }
+
+    #[test]
+    fn default_layout_places_plugins_and_skips_patchers() {
+        let layout = Layout::default();
+        assert_eq!(
+            map(&layout, "BepInEx/plugins/Author-Mod/Mod.dll"),
+            Some((Target::Lib, "Author-Mod/Mod.dll"))
+        );
+        assert_eq!(
+            map(&layout, "plugins/Mod.xml"),
+            Some((Target::Lib, "Mod.xml"))
+        );
+        assert_eq!(map(&layout, "Mod.dll"), Some((Target::Lib, "Mod.dll")));
+        // Folders matched by the last `**` are kept
+        assert_eq!(
+            map(&layout, "some/dir/Mod.DLL"),
+            Some((Target::Lib, "some/dir/Mod.DLL"))
+        );
+        assert_eq!(
+            map(&layout, "BepInEx/patchers/Patcher.dll"),
+            Some((Target::Skip, "Patcher.dll"))
+        );
+        assert_eq!(
+            map(&layout, "BepInEx/core/BepInEx.dll"),
+            Some((Target::Skip, "BepInEx.dll"))
+        );
+        assert_eq!(map(&layout, "README.md"), None);
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let layout = Layout::parse("config/*.cfg = content/config ; **/*=skip;").unwrap();
+        assert_eq!(
+            map(&layout, "config/Mod.cfg"),
+            Some((Target::Folder("content/config".to_string()), "Mod.cfg"))
+        );
+        assert_eq!(
+            map(&layout, "config/nested/Mod.cfg"),
+            Some((Target::Skip, "config/nested/Mod.cfg"))
+        );
+        assert_eq!(map(&layout, "icon.png"), Some((Target::Skip, "icon.png")));
+    }
+
+    #[test]
+    fn wildcards_match_within_a_segment() {
+        let layout = Layout::parse("Mod*.dll=lib;*Core*=lib").unwrap();
+        assert!(layout.map("ModCore.dll").is_some());
+        assert!(layout.map("Mod.dll").is_some());
+        assert!(layout.map("MyCoreLib").is_some());
+        assert!(layout.map("dir/ModCore.dll").is_none());
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!(matches!(
+            Layout::parse("plugins"),
+            Err(LayoutError::MissingTarget(_))
+        ));
+        assert!(matches!(
+            Layout::parse("/=lib"),
+            Err(LayoutError::EmptyPattern(_))
+        ));
+        assert!(matches!(
+            Layout::parse("**/*.dll=../lib"),
+            Err(LayoutError::InvalidTarget(_))
+        ));
+        assert!(matches!(
+            Layout::parse("**/*.dll=content//x"),
+            Err(LayoutError::InvalidTarget(_))
+        ));
+        assert!(Layout::parse("").unwrap().map("Mod.dll").is_none());
+    }
+}
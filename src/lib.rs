@@ -0,0 +1,620 @@
+//! A NuGet v3 feed serving Thunderstore packages, converting them to nupkgs on demand.
+//!
+//! Build an [`AppState`] from a [`ServerConfig`], start it, and serve [`router`]:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), ts_nuget::CatalogError> {
+//! let state = ts_nuget::AppState::new(ts_nuget::ServerConfig::new("http://localhost:5000"))?;
+//! state.start().await;
+//! let listener = tokio::net::TcpListener::bind(("0.0.0.0", 5000)).await.unwrap();
+//! axum::serve(listener, ts_nuget::router(state)).await.unwrap();
+//! # Ok(())
+//! # }
+//! ```
+
+use axum::body::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::RwLock, time::Instant};
+
+use axum::extract::{FromRef, FromRequestParts, Path, Query, RawPathParams, State};
+use axum::http::{header, request::Parts, StatusCode};
+use axum::response::{IntoResponse, Json, Response};
+use axum::Router;
+
+#[derive(Serialize)]
+struct Resource {
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(rename = "@type")]
+    pub res_type: String,
+}
+
+mod assembly;
+mod catalog;
+pub mod config;
+mod index;
+pub mod layout;
+pub mod metadata;
+
+pub use crate::catalog::CatalogError;
+use crate::metadata::{AutocompleteQuery, Cache, PackageKey, SearchQuery};
+
+pub mod nupkg;
+pub mod package_type;
+
+use crate::nupkg::{ConvertOptions, Nupkg, NupkgStore};
+use crate::upstream::{Thunderstore, Upstream};
+
+mod symbols;
+pub mod upstream;
+mod version;
+
+type SharedState = Arc<RwLock<Cache>>;
+
+pub const DEFAULT_CACHE: Duration = Duration::from_secs(5 * 60);
+
+/// Everything needed to run one feed.
+pub struct ServerConfig {
+    /// The public url the feed is reached at, used in every document it serves.
+    pub base_url: String,
+    /// Holds the snapshot, catalog, converted packages and symbols.
+    pub data_dir: PathBuf,
+    /// Where package lists and archives come from.
+    pub upstream: Arc<dyn Upstream>,
+    /// How often package lists are refetched from upstream.
+    pub refresh_interval: Duration,
+    /// Only these communities are fetched and served, every community if None.
+    pub communities: Option<HashSet<String>>,
+    pub conversion: ConvertOptions,
+    /// Bytes of converted packages kept on disk, evicting the least recently used. Unbounded if None.
+    pub nupkg_cache_size: Option<u64>,
+}
+
+impl ServerConfig {
+    /// A feed of thunderstore.io at `base_url`, keeping its data in the working directory.
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.to_string(),
+            data_dir: PathBuf::from("."),
+            upstream: Arc::new(Thunderstore::default()),
+            refresh_interval: DEFAULT_CACHE,
+            communities: None,
+            conversion: ConvertOptions::default(),
+            nupkg_cache_size: None,
+        }
+    }
+}
+
+/// A feed's package cache and settings, shared by every request.
+#[derive(Clone)]
+pub struct AppState {
+    pub cache: Arc<RwLock<Cache>>,
+    pub conversion: Arc<ConvertOptions>,
+    pub nupkgs: Arc<NupkgStore>,
+    pub refresh_interval: Duration,
+}
+
+impl AppState {
+    /// Loads the feed's catalog from its data directory. Packages aren't fetched until [`AppState::start`].
+    pub fn new(config: ServerConfig) -> Result<Self, CatalogError> {
+        let mut cache = Cache::new(config.upstream, &config.base_url, config.data_dir)?;
+        cache.enabled_communities = config.communities;
+        let nupkgs = NupkgStore::open(&cache.data_dir, config.nupkg_cache_size);
+
+        Ok(Self {
+            cache: Arc::new(RwLock::new(cache)),
+            conversion: Arc::new(config.conversion),
+            nupkgs: Arc::new(nupkgs),
+            refresh_interval: config.refresh_interval,
+        })
+    }
+
+    /// Serves the last snapshot right away, then refreshes from upstream in the background
+    /// and keeps doing so every `refresh_interval`.
+    pub async fn start(&self) {
+        match Cache::load_snapshot(&self.cache).await {
+            Ok(true) => println!(
+                "Loaded {} packages from snapshot",
+                self.cache.read().await.packages.len()
+            ),
+            Ok(false) => {
+                println!("No cache snapshot, serving an empty feed until the first refresh")
+            }
+            Err(err) => eprintln!("Ignoring unreadable cache snapshot; {err}"),
+        }
+
+        let refresh_state = self.cache.clone();
+        tokio::spawn(async move {
+            let cache_start = Instant::now();
+            match Cache::cache(&refresh_state).await {
+                Ok(_) => println!(
+                    "Took {} seconds to get full cache",
+                    cache_start.elapsed().as_secs_f64()
+                ),
+                Err(err) => eprintln!("Failed to get cache! {err:?}"),
+            }
+        });
+
+        Cache::enable_auto_update(self.cache.clone(), self.refresh_interval).await;
+    }
+}
+
+impl FromRef<AppState> for SharedState {
+    fn from_ref(state: &AppState) -> Self {
+        state.cache.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<NupkgStore> {
+    fn from_ref(state: &AppState) -> Self {
+        state.nupkgs.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<ConvertOptions> {
+    fn from_ref(state: &AppState) -> Self {
+        state.conversion.clone()
+    }
+}
+
+/// The feed's routes: `/nuget/v3/index.json` for every community, `/c/{community}/nuget/v3/index.json`
+/// for a single one, plus the catalog, symbol server and `/status`.
+pub fn router(state: AppState) -> Router {
+    let feed = Router::new()
+        .route("/index.json", axum::routing::get(get_services))
+        .route("/base/{id}/index.json", axum::routing::get(get_base))
+        .route(
+            "/base/{id}/{ver}/{filename}",
+            axum::routing::get(get_download),
+        )
+        .route("/package/{id}/index.json", axum::routing::get(get_registry))
+        .route(
+            "/package/{id}/page/{lower}/{upper}",
+            axum::routing::get(get_registry_page),
+        )
+        .route(
+            "/package/{id}/{leaf}",
+            axum::routing::get(get_registry_leaf),
+        )
+        .route("/search", axum::routing::get(search))
+        .route("/autocomplete", axum::routing::get(autocomplete));
+
+    Router::new()
+        .nest("/nuget/v3", feed.clone())
+        .nest("/c/{community}/nuget/v3", feed)
+        .route(
+            "/nuget/v3/catalog/index.json",
+            axum::routing::get(get_catalog),
+        )
+        .route(
+            "/nuget/v3/catalog/page/{page}",
+            axum::routing::get(get_catalog_page),
+        )
+        .route(
+            "/nuget/v3/catalog/data/{commit}/{leaf}",
+            axum::routing::get(get_catalog_leaf),
+        )
+        .route("/status", axum::routing::get(get_status))
+        .route(
+            "/symbols/{file}/{id}/{file2}",
+            axum::routing::get(get_symbols),
+        )
+        .layer(tower_http::compression::CompressionLayer::new())
+        .with_state(state)
+}
+
+// The community a feed route is scoped to, if any
+struct Community(Option<String>);
+
+impl<S: Send + Sync> FromRequestParts<S> for Community {
+    type Rejection = <RawPathParams as FromRequestParts<S>>::Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let params = RawPathParams::from_request_parts(parts, state).await?;
+        Ok(Self(
+            params
+                .iter()
+                .find(|(key, _)| *key == "community")
+                .map(|(_, value)| value.to_string()),
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+struct PackagePath {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct PagePath {
+    id: String,
+    lower: String,
+    upper: String,
+}
+
+#[derive(Deserialize)]
+struct LeafPath {
+    id: String,
+    leaf: String,
+}
+
+#[derive(Deserialize)]
+struct DownloadPath {
+    id: String,
+    ver: String,
+}
+
+async fn get_services(
+    Community(community): Community,
+    State(state): State<SharedState>,
+) -> Result<Json<Value>, StatusCode> {
+    let cache = state.read().await;
+    let url = match &community {
+        Some(community) => {
+            if !cache.feeds.contains_key(community) {
+                return Err(StatusCode::NOT_FOUND);
+            }
+            format!("{}/c/{community}", cache.base_url)
+        }
+        None => cache.base_url.clone(),
+    };
+
+    let mut resources = vec![
+        Resource {
+            id: format!("{url}/nuget/v3/base"),
+            res_type: "PackageBaseAddress/3.0.0".to_string(),
+        },
+        Resource {
+            id: format!("{url}/nuget/v3/search"),
+            res_type: "SearchQueryService".to_string(),
+        },
+        Resource {
+            id: format!("{url}/nuget/v3/search"),
+            res_type: "SearchQueryService/3.0.0-beta".to_string(),
+        },
+        Resource {
+            id: format!("{url}/nuget/v3/search"),
+            res_type: "SearchQueryService/3.0.0-rc".to_string(),
+        },
+        Resource {
+            id: format!("{url}/nuget/v3/autocomplete"),
+            res_type: "SearchAutocompleteService".to_string(),
+        },
+        Resource {
+            id: format!("{url}/nuget/v3/autocomplete"),
+            res_type: "SearchAutocompleteService/3.0.0-beta".to_string(),
+        },
+        Resource {
+            id: format!("{url}/nuget/v3/autocomplete"),
+            res_type: "SearchAutocompleteService/3.0.0-rc".to_string(),
+        },
+        Resource {
+            id: format!("{url}/nuget/v3/nullpublish"),
+            res_type: "PackagePublish/2.0.0".to_string(),
+        },
+        Resource {
+            id: format!("{url}/nuget/v3/package"),
+            res_type: "RegistrationsBaseUrl".to_string(),
+        },
+    ];
+    // The catalog and symbols cover every community, so they're only advertised on the global feed
+    if community.is_none() {
+        resources.push(Resource {
+            id: format!("{url}/nuget/v3/catalog/index.json"),
+            res_type: "Catalog/3.0.0".to_string(),
+        });
+        // Not a type NuGet clients look for, but it tells people where to point their debugger
+        resources.push(Resource {
+            id: format!("{url}/symbols"),
+            res_type: "SymbolServer/SSQP".to_string(),
+        });
+    }
+
+    Ok(Json(json!({
+        "version": "3.0.0",
+        "resources": resources,
+    })))
+}
+
+async fn get_base(
+    Community(community): Community,
+    Path(PackagePath { id }): Path<PackagePath>,
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let cache = state.read().await;
+
+    cache
+        .feed(community.as_deref())
+        .ok_or(StatusCode::NOT_FOUND)?
+        .package(&PackageKey::try_from(id).map_err(|_| StatusCode::BAD_REQUEST)?)
+        .map(|package| {
+            let versions = package
+                .versions
+                .iter()
+                .map(|version| version.catalogEntry.version.to_lowercase())
+                .collect::<Vec<_>>();
+            (
+                [(
+                    "Cache-Control",
+                    format!(
+                        "max-age={}",
+                        cache.cache_duration.unwrap_or(DEFAULT_CACHE).as_secs() / 2
+                    ),
+                )],
+                Json(json!({ "versions": versions })),
+            )
+        })
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_download(
+    Community(community): Community,
+    Path(DownloadPath { id, ver }): Path<DownloadPath>,
+    State(state): State<SharedState>,
+    State(conversion): State<Arc<ConvertOptions>>,
+    State(nupkgs): State<Arc<NupkgStore>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let (version, upstream, types, data_dir) = {
+        let cache = state.read().await;
+        let version = cache
+            .feed(community.as_deref())
+            .ok_or(StatusCode::NOT_FOUND)?
+            .package(&PackageKey::try_from(id).map_err(|_| StatusCode::BAD_REQUEST)?)
+            .and_then(|pkg| pkg.version(&ver))
+            .ok_or(StatusCode::NOT_FOUND)?
+            .clone();
+        (
+            version,
+            cache.upstream.clone(),
+            cache.package_types.clone(),
+            cache.data_dir.clone(),
+        )
+    };
+
+    let response = async {
+        Nupkg::get_for_pkg(&version, upstream, conversion, nupkgs, types, &data_dir)
+            .await?
+            .get_body()
+            .await
+    }
+    .await
+    .map_err(|err| {
+        eprintln!(
+            "Failed to convert {} {}: {err}",
+            version.catalogEntry.id, version.catalogEntry.version
+        );
+        err.status()
+    })?;
+
+    Ok(([("Cache-Control", "max-age=1209600, immutable")], response))
+}
+
+async fn get_symbols(
+    Path((file, id, file2)): Path<(String, String, String)>,
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !file.eq_ignore_ascii_case(&file2) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let data_dir = state.read().await.data_dir.clone();
+    let body = symbols::get(&data_dir, &file, &id)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream"),
+            (header::CACHE_CONTROL, "max-age=1209600, immutable"),
+        ],
+        body,
+    ))
+}
+
+async fn get_registry(
+    Community(community): Community,
+    Path(PackagePath { id }): Path<PackagePath>,
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let cache = state.read().await;
+
+    let feed = cache
+        .feed(community.as_deref())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    feed.package(&PackageKey::try_from(id).map_err(|_| StatusCode::BAD_REQUEST)?)
+        .map(|pkg| {
+            (
+                [(
+                    "Cache-Control",
+                    format!(
+                        "max-age={}",
+                        cache.cache_duration.unwrap_or(DEFAULT_CACHE).as_secs() / 2
+                    ),
+                )],
+                Json(feed.document(pkg.index())),
+            )
+        })
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_registry_page(
+    Community(community): Community,
+    Path(PagePath { id, lower, upper }): Path<PagePath>,
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let cache = state.read().await;
+    let upper = upper.strip_suffix(".json").ok_or(StatusCode::NOT_FOUND)?;
+
+    let feed = cache
+        .feed(community.as_deref())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    feed.package(&PackageKey::try_from(id).map_err(|_| StatusCode::BAD_REQUEST)?)
+        .and_then(|pkg| pkg.page(&lower, upper))
+        .map(|page| {
+            (
+                [(
+                    "Cache-Control",
+                    format!(
+                        "max-age={}",
+                        cache.cache_duration.unwrap_or(DEFAULT_CACHE).as_secs() / 2
+                    ),
+                )],
+                Json(feed.document(page)),
+            )
+        })
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_registry_leaf(
+    Community(community): Community,
+    Path(LeafPath { id, leaf }): Path<LeafPath>,
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let cache = state.read().await;
+    let version = leaf.strip_suffix(".json").ok_or(StatusCode::NOT_FOUND)?;
+
+    let feed = cache
+        .feed(community.as_deref())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    feed.package(&PackageKey::try_from(id).map_err(|_| StatusCode::BAD_REQUEST)?)
+        .and_then(|pkg| pkg.leaf(version))
+        .map(|leaf| {
+            (
+                [(
+                    "Cache-Control",
+                    format!(
+                        "max-age={}",
+                        cache.cache_duration.unwrap_or(DEFAULT_CACHE).as_secs() / 2
+                    ),
+                )],
+                Json(feed.document(leaf)),
+            )
+        })
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_catalog(State(state): State<SharedState>) -> Json<Value> {
+    Json(state.read().await.catalog.index())
+}
+
+async fn get_catalog_page(
+    Path(page): Path<String>,
+    State(state): State<SharedState>,
+) -> Result<Json<Value>, StatusCode> {
+    let page = page
+        .strip_suffix(".json")
+        .and_then(|x| x.parse().ok())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    state
+        .read()
+        .await
+        .catalog
+        .page(page)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_catalog_leaf(
+    Path((commit, leaf)): Path<(String, String)>,
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Leaves never change once written
+    let catalog = state.read().await.catalog.clone();
+    let leaf = catalog
+        .leaf(&commit, &leaf)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok((
+        [("Cache-Control", "max-age=1209600, immutable")],
+        Json(leaf),
+    ))
+}
+
+async fn get_status(
+    State(state): State<SharedState>,
+    State(nupkgs): State<Arc<NupkgStore>>,
+) -> Json<Value> {
+    let cache = state.read().await;
+
+    Json(json!({
+        "packages": cache.packages.len(),
+        "communities": cache.communities,
+        "nupkg_cache_size": nupkgs.size(),
+    }))
+}
+
+enum SearchResponse {
+    All(Bytes),
+    Query(Json<Value>),
+}
+
+impl IntoResponse for SearchResponse {
+    fn into_response(self) -> Response {
+        match self {
+            SearchResponse::All(all) => {
+                ([(header::CONTENT_TYPE, "application/json")], all).into_response()
+            }
+            SearchResponse::Query(query) => query.into_response(),
+        }
+    }
+}
+
+async fn search(
+    Community(community): Community,
+    Query(params): Query<SearchQuery>,
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let cache = state.read().await;
+    let feed = cache
+        .feed(community.as_deref())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let body = if params.is_empty() {
+        SearchResponse::All(feed.all_packages())
+    } else {
+        SearchResponse::Query(Json(feed.document(feed.search(params))))
+    };
+
+    Ok((
+        [(
+            "Cache-Control",
+            format!(
+                "max-age={}",
+                cache.cache_duration.unwrap_or(DEFAULT_CACHE).as_secs() / 2
+            ),
+        )],
+        body,
+    ))
+}
+
+async fn autocomplete(
+    Community(community): Community,
+    Query(params): Query<AutocompleteQuery>,
+    State(state): State<SharedState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let cache = state.read().await;
+    let result = cache
+        .feed(community.as_deref())
+        .ok_or(StatusCode::NOT_FOUND)?
+        .autocomplete(params);
+
+    Ok((
+        [(
+            "Cache-Control",
+            format!(
+                "max-age={}",
+                cache.cache_duration.unwrap_or(DEFAULT_CACHE).as_secs() / 2
+            ),
+        )],
+        Json(result),
+    ))
+}
@@ -1,7 +1,21 @@
+use crate::catalog::{Catalog, CatalogError, CATALOG_DIR};
+use crate::index::{Scope, SearchIndex, Term};
+use crate::package_type::{PackageType, PackageTypes};
+use crate::upstream::{FetchedCommunity, FetchedIndex, PackageIndex, Upstream, UpstreamError};
+use crate::version::Version;
 use axum::body::Bytes;
 use futures::{pin_mut, FutureExt};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use serde_json::Value;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
 use tokio::sync::RwLock;
 use tokio_util::sync::CancellationToken;
 
@@ -59,61 +73,536 @@ mod key {
 
 pub use key::*;
 
-#[derive(Default)]
+const SNAPSHOT_FILE: &str = "snapshot.json";
+// Whole lists are still refetched this often, for download counts and anything else the package
+// index doesn't show
+const WHOLE_LIST_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Past this many packages, fetching them one by one costs more than a community's whole list
+const MAX_PACKAGE_FETCHES: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Failed to access snapshot; {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Snapshot is malformed; {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// The raw upstream package lists of every community, kept on disk between runs.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct Snapshot {
+    pub communities: HashMap<String, Arc<Vec<TSPackage>>>,
+    #[serde(default)]
+    pub validators: HashMap<String, Validators>,
+    /// Upstream's package index at the last refresh, to tell which packages changed since.
+    #[serde(default)]
+    pub index: Option<PackageIndex>,
+    /// When each community's whole list was last fetched, in seconds since the epoch.
+    #[serde(default)]
+    pub fetched: HashMap<String, u64>,
+}
+
+// Packages to refetch according to upstream's package index
+struct IndexUpdates {
+    // By full name, including ones upstream no longer has
+    changed: HashSet<String>,
+    // Communities listing packages new to the index
+    relisted: HashSet<String>,
+}
+
+/// What we last saw of a community's package list, to skip refetching and reparsing it.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub hash: Option<u64>,
+}
+
+impl Snapshot {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        let file = BufReader::new(std::fs::File::open(path)?);
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Written next to the real file first so a crash never leaves a truncated snapshot.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let path = path.as_ref();
+        let temp_path = path.with_extension("tmp");
+
+        let mut file = BufWriter::new(std::fs::File::create(&temp_path)?);
+        serde_json::to_writer(&mut file, self)?;
+        file.flush()?;
+        drop(file);
+
+        std::fs::rename(temp_path, path)?;
+        Ok(())
+    }
+}
+
+/// How refreshing a community has been going, as reported by `/status`.
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct CommunityStatus {
+    pub packages: usize,
+    /// Unix timestamp of the last refresh that got the community's packages.
+    pub last_success: Option<u64>,
+    /// Unix timestamp of the last refresh that didn't.
+    pub last_failure: Option<u64>,
+    /// Why that refresh failed, cleared by the next one that succeeds.
+    pub last_error: Option<String>,
+}
+
+/// Every package of every community, converted to NuGet documents and refreshed from upstream.
+///
+/// Shared behind an `RwLock`; refreshing only takes the write lock to swap in finished results.
 pub struct Cache {
     auto_update: Option<Arc<CancellationToken>>,
+    pub upstream: Arc<dyn Upstream>,
+    /// Communities to fetch, every one upstream has if None.
+    pub enabled_communities: Option<HashSet<String>>,
+    /// Where this feed is served from, used to build every url in its documents.
+    pub base_url: String,
+    /// Holds the snapshot, catalog, converted packages and symbols.
+    pub data_dir: PathBuf,
     pub cache_duration: Option<Duration>,
     pub packages: HashMap<PackageKey<'static>, NugetPackage>,
     pub all_packages: Bytes,
+    pub feeds: HashMap<String, CommunityFeed>,
+    pub(crate) index: SearchIndex,
+    pub communities: HashMap<String, CommunityStatus>,
+    pub(crate) catalog: Catalog,
+    pub package_types: Arc<PackageTypes>,
+    snapshot: Snapshot,
+    // Each refresh diffs against and commits on top of the one before it
+    refreshing: Arc<tokio::sync::Mutex<()>>,
 }
 
 impl Cache {
-    pub async fn cache(cache: &RwLock<Cache>) -> Result<(), reqwest::Error> {
-        let mut next_option =
-            Some("https://thunderstore.io/api/experimental/community/".to_string());
-        let mut communities = vec![];
-
-        while let Some(next) = next_option {
-            let list = reqwest::get(next).await?.json::<TSCommunityList>().await?;
-            communities.extend(list.results.into_iter().map(|x| x.identifier));
-            next_option = list.pagination.next_link;
-        }
-
-        let packages = futures::future::join_all(communities.into_iter().map(|comm| async move {
-            reqwest::get(format!("https://thunderstore.io/c/{comm}/api/v1/package/"))
-                .await?
-                .json::<Vec<TSPackage>>()
-                .await
+    /// An empty cache serving from `base_url`, with its catalog loaded from `data_dir`.
+    pub fn new(
+        upstream: Arc<dyn Upstream>,
+        base_url: &str,
+        data_dir: impl Into<PathBuf>,
+    ) -> Result<Self, CatalogError> {
+        let base_url = base_url.trim_end_matches('/').to_string();
+        let data_dir = data_dir.into();
+        let catalog = Catalog::load(data_dir.join(CATALOG_DIR), &base_url)?;
+        let package_types = Arc::new(PackageTypes::load(&data_dir));
+
+        Ok(Self {
+            auto_update: None,
+            upstream,
+            enabled_communities: None,
+            base_url,
+            data_dir,
+            cache_duration: None,
+            packages: Default::default(),
+            all_packages: Default::default(),
+            feeds: Default::default(),
+            index: Default::default(),
+            communities: Default::default(),
+            catalog,
+            package_types,
+            snapshot: Default::default(),
+            refreshing: Default::default(),
+        })
+    }
+
+    /// Refetches every community from upstream, committing changes to the catalog and the snapshot.
+    ///
+    /// Only fails if the community list can't be fetched; a failing community keeps its old packages.
+    /// Concurrent calls wait for each other.
+    pub async fn cache(cache: &RwLock<Cache>) -> Result<(), UpstreamError> {
+        let (upstream, enabled, refreshing) = {
+            let cache = cache.read().await;
+            (
+                cache.upstream.clone(),
+                cache.enabled_communities.clone(),
+                cache.refreshing.clone(),
+            )
+        };
+        let _refreshing = refreshing.lock().await;
+
+        let mut communities = upstream.communities().await?;
+        if let Some(enabled) = &enabled {
+            communities.retain(|comm| enabled.contains(comm));
+        }
+
+        // Communities that failed to refresh keep whatever they had before
+        let (previous, mut statuses, catalog) = {
+            let cache = cache.read().await;
+            (
+                cache.snapshot.clone(),
+                cache.communities.clone(),
+                cache.catalog.clone(),
+            )
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (index, updates) = Self::index_updates(&*upstream, &previous).await;
+
+        let results = futures::future::join_all(communities.into_iter().map(|comm| {
+            let validators = previous
+                .communities
+                .contains_key(&comm)
+                .then(|| previous.validators.get(&comm).cloned())
+                .flatten();
+            let stale = updates
+                .as_ref()
+                .filter(|updates| !updates.relisted.contains(&comm))
+                .filter(|_| {
+                    previous.fetched.get(&comm).is_some_and(|fetched| {
+                        now.saturating_sub(*fetched) < WHOLE_LIST_INTERVAL.as_secs()
+                    })
+                })
+                .and_then(|updates| {
+                    let packages = previous.communities.get(&comm)?;
+                    let stale: HashSet<_> = packages
+                        .iter()
+                        .filter(|x| updates.changed.contains(&x.full_name))
+                        .map(|x| x.full_name.as_str())
+                        .collect();
+                    (stale.len() <= MAX_PACKAGE_FETCHES).then_some((packages, stale))
+                });
+            let upstream = &upstream;
+            async move {
+                if let Some((packages, stale)) = stale {
+                    match Self::update_packages(&**upstream, &comm, packages, &stale).await {
+                        Ok(packages) => {
+                            let fetched = FetchedCommunity {
+                                // The list no longer matches the body they were taken from
+                                validators: match packages {
+                                    Some(_) => Validators::default(),
+                                    None => validators.unwrap_or_default(),
+                                },
+                                packages,
+                            };
+                            return (comm, Ok(fetched), false);
+                        }
+                        Err(err) => eprintln!(
+                            "Failed to update community {comm} from the package index, fetching its whole list! {err}"
+                        ),
+                    }
+                }
+                let fetched = upstream.packages(&comm, validators).await;
+                (comm, fetched, true)
+            }
         }))
         .await;
 
-        let packages: HashMap<_, _> = packages
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()?
-            .into_iter()
-            .flatten()
+        let mut changed = false;
+        let mut snapshot = Snapshot {
+            index,
+            ..Default::default()
+        };
+        let mut refreshed = HashMap::new();
+
+        for (comm, result, whole) in results {
+            let mut status = statuses.remove(&comm).unwrap_or_default();
+            let result = result.and_then(|fetched| {
+                let packages = match fetched.packages {
+                    Some(packages) => {
+                        changed = true;
+                        Arc::new(packages)
+                    }
+                    // Failing leaves no validators behind, so the next refresh fetches it in full
+                    None => previous
+                        .communities
+                        .get(&comm)
+                        .cloned()
+                        .ok_or(UpstreamError::UnknownUnchanged)?,
+                };
+                Ok((packages, fetched.validators))
+            });
+            match result {
+                Ok((packages, validators)) => {
+                    status.last_success = Some(now);
+                    status.last_error = None;
+                    snapshot.communities.insert(comm.clone(), packages);
+                    snapshot.validators.insert(comm.clone(), validators);
+                    let fetched = match whole {
+                        true => Some(now),
+                        false => previous.fetched.get(&comm).copied(),
+                    };
+                    if let Some(fetched) = fetched {
+                        snapshot.fetched.insert(comm.clone(), fetched);
+                    }
+                }
+                // Leaves no fetch time behind, so the next refresh gets its whole list rather than
+                // missing changes the index has moved past
+                Err(err) => {
+                    eprintln!(
+                        "Failed to refresh community {comm}, keeping previous packages! {err}"
+                    );
+                    status.last_failure = Some(now);
+                    status.last_error = Some(err.to_string());
+                    if let Some(packages) = previous.communities.get(&comm) {
+                        snapshot.communities.insert(comm.clone(), packages.clone());
+                    }
+                    if let Some(validators) = previous.validators.get(&comm) {
+                        snapshot.validators.insert(comm.clone(), validators.clone());
+                    }
+                }
+            }
+            refreshed.insert(comm, status);
+        }
+
+        let changed = changed
+            || snapshot.communities.len() != previous.communities.len()
+            || snapshot
+                .communities
+                .keys()
+                .any(|comm| !previous.communities.contains_key(comm));
+
+        if !changed {
+            let mut cache = cache.write().await;
+            for (comm, status) in &mut refreshed {
+                status.packages = snapshot.communities.get(comm).map_or(0, |p| p.len());
+            }
+            cache.communities = refreshed;
+            cache.snapshot = snapshot;
+            return Ok(());
+        }
+
+        let (committed, catalog) = {
+            let snapshot = snapshot.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut catalog = catalog;
+                let committed = catalog.commit(&previous, &snapshot);
+                (committed, catalog)
+            })
+            .await
+            .unwrap()
+        };
+        match committed {
+            Ok(0) => (),
+            Ok(count) => {
+                println!("Added {count} catalog leaves");
+                cache.write().await.catalog = catalog;
+            }
+            Err(err) => {
+                // Keeping the previous snapshot makes the next refresh diff the same changes again
+                eprintln!("Failed to update catalog, keeping previous packages! {err}");
+                return Ok(());
+            }
+        }
+
+        Self::apply(cache, snapshot.clone(), refreshed).await;
+
+        let path = cache.read().await.data_dir.join(SNAPSHOT_FILE);
+        if let Err(err) = tokio::task::spawn_blocking(move || snapshot.save(path))
+            .await
+            .unwrap()
+        {
+            eprintln!("Failed to save cache snapshot! {err}");
+        }
+
+        Ok(())
+    }
+
+    // The packages upstream's package index shows changed since the last refresh, and the index to
+    // diff the next refresh against. No updates if every community needs its whole list
+    async fn index_updates(
+        upstream: &dyn Upstream,
+        previous: &Snapshot,
+    ) -> (Option<PackageIndex>, Option<IndexUpdates>) {
+        let validators = previous.index.as_ref().map(|x| x.validators.clone());
+        let index = match upstream.package_index(validators).await {
+            Ok(Some(FetchedIndex {
+                packages: Some(packages),
+                validators,
+            })) => PackageIndex {
+                packages,
+                validators,
+            },
+            Ok(Some(FetchedIndex {
+                packages: None,
+                validators,
+            })) => match &previous.index {
+                Some(index) => PackageIndex {
+                    packages: index.packages.clone(),
+                    validators,
+                },
+                None => return (None, None),
+            },
+            Ok(None) => return (None, None),
+            Err(err) => {
+                eprintln!("Failed to fetch the package index, fetching whole lists! {err}");
+                return (previous.index.clone(), None);
+            }
+        };
+        let Some(previous_index) = &previous.index else {
+            return (Some(index), None);
+        };
+
+        let (changed, added) = index.changes(previous_index);
+        if added.len() > MAX_PACKAGE_FETCHES {
+            return (Some(index), None);
+        }
+        // Only whole lists have packages new to a community
+        let listings =
+            futures::future::try_join_all(added.iter().map(|name| upstream.listings(name))).await;
+        let relisted = match listings {
+            Ok(listings) => listings.into_iter().flatten().collect(),
+            Err(err) => {
+                eprintln!(
+                    "Failed to find where new packages are listed, fetching whole lists! {err}"
+                );
+                return (Some(index), None);
+            }
+        };
+        let changed = changed.into_iter().map(String::from).collect();
+
+        (Some(index), Some(IndexUpdates { changed, relisted }))
+    }
+
+    // A community's list with its `stale` packages refetched, or None if there are none
+    async fn update_packages(
+        upstream: &dyn Upstream,
+        community: &str,
+        packages: &[TSPackage],
+        stale: &HashSet<&str>,
+    ) -> Result<Option<Vec<TSPackage>>, UpstreamError> {
+        if stale.is_empty() {
+            return Ok(None);
+        }
+
+        let stale: Vec<_> = packages
+            .iter()
+            .filter(|x| stale.contains(x.full_name.as_str()))
+            .collect();
+        let refetched =
+            futures::future::try_join_all(stale.iter().map(|x| upstream.package(community, x)))
+                .await?;
+        let mut refetched: HashMap<_, _> = stale
+            .iter()
+            .map(|x| x.full_name.as_str())
+            .zip(refetched)
+            .collect();
+
+        // Packages the community no longer lists are dropped
+        Ok(Some(
+            packages
+                .iter()
+                .filter_map(|x| match refetched.remove(x.full_name.as_str()) {
+                    Some(refetched) => refetched,
+                    None => Some(x.clone()),
+                })
+                .collect(),
+        ))
+    }
+
+    /// Serves the packages saved by the last refresh. Returns false if there was no snapshot to load.
+    pub async fn load_snapshot(cache: &RwLock<Cache>) -> Result<bool, SnapshotError> {
+        let path = cache.read().await.data_dir.join(SNAPSHOT_FILE);
+        let snapshot = match tokio::task::spawn_blocking(|| Snapshot::load(path))
+            .await
+            .unwrap()
+        {
+            Ok(snapshot) => snapshot,
+            Err(SnapshotError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(false)
+            }
+            Err(err) => return Err(err),
+        };
+
+        let statuses = snapshot
+            .communities
+            .keys()
+            .map(|comm| (comm.clone(), CommunityStatus::default()))
+            .collect();
+        Self::apply(cache, snapshot, statuses).await;
+
+        Ok(true)
+    }
+
+    async fn apply(
+        cache: &RwLock<Cache>,
+        snapshot: Snapshot,
+        mut statuses: HashMap<String, CommunityStatus>,
+    ) {
+        for (comm, packages) in &snapshot.communities {
+            if let Some(status) = statuses.get_mut(comm) {
+                status.packages = packages.len();
+            }
+        }
+
+        let (base_url, package_types) = {
+            let cache = cache.read().await;
+            (cache.base_url.clone(), cache.package_types.clone())
+        };
+        let packages: HashMap<_, _> = snapshot
+            .communities
+            .values()
+            .flat_map(|packages| packages.iter())
             .map(|p| {
                 (
                     PackageKey::try_from(p.full_name.clone()).unwrap(),
-                    NugetPackage::from(p),
+                    NugetPackage::new(p, &base_url),
                 )
             })
             .collect();
 
-        let all_package_string = serde_json::to_string(&SearchResult {
-            totalHits: packages.len(),
-            data: packages.values().map(|p| p.into()).collect(),
-        })
+        let mut index = SearchIndex::default();
+        let mut indexed = HashSet::new();
+        for pkg in snapshot.communities.values().flat_map(|x| x.iter()) {
+            if indexed.insert(pkg.full_name.as_str()) {
+                let description = pkg.latest().map_or("", |x| x.description.as_str());
+                index.add(&pkg.full_name, description, &pkg.categories);
+            }
+        }
+
+        // Types found by conversions after this show up here from the next refresh
+        let all_package_string = serde_json::to_string(&search(
+            packages.values(),
+            &index,
+            &package_types,
+            &SearchQuery::default(),
+        ))
         .unwrap();
 
+        let feeds = snapshot
+            .communities
+            .iter()
+            .map(|(comm, community_packages)| {
+                let keys: HashSet<_> = community_packages
+                    .iter()
+                    .map(|p| PackageKey::try_from(p.full_name.clone()).unwrap())
+                    .collect();
+                let url = format!("{base_url}/c/{comm}/nuget/v3/");
+                let mut all_packages = serde_json::to_value(search(
+                    keys.iter().map(|key| &packages[key]),
+                    &index,
+                    &package_types,
+                    &SearchQuery::default(),
+                ))
+                .unwrap();
+                rescope(&mut all_packages, &format!("{base_url}/nuget/v3/"), &url);
+
+                (
+                    comm.clone(),
+                    CommunityFeed {
+                        url,
+                        packages: keys,
+                        all_packages: serde_json::to_string(&all_packages).unwrap().into(),
+                    },
+                )
+            })
+            .collect();
+
         let mut cache = cache.write().await;
 
         cache.packages = packages;
         cache.all_packages = all_package_string.into();
-
-        Ok(())
+        cache.feeds = feeds;
+        cache.index = index;
+        cache.communities = statuses;
+        cache.snapshot = snapshot;
     }
 
+    /// Refreshes every `timeout` until [`Cache::disable_auto_update`], and has clients cache for half that.
     pub async fn enable_auto_update(cache: Arc<RwLock<Cache>>, timeout: Duration) {
         let mut s = cache.write().await;
 
@@ -141,52 +630,365 @@ impl Cache {
         });
     }
 
-    #[allow(dead_code)]
+    /// Stops the refreshes started by [`Cache::enable_auto_update`].
     pub fn disable_auto_update(cache: &mut Cache) {
         if let Some(token) = cache.auto_update.take() {
             token.cancel();
         }
     }
 
-    pub fn search(&self, q: SearchQuery) -> SearchResult {
-        let mut results: &mut dyn Iterator<Item = &NugetPackage> = &mut self.packages.values();
+    /// The packages of one community, or of all of them. None if there's no such community.
+    pub fn feed(&self, community: Option<&str>) -> Option<Feed<'_>> {
+        let community = match community {
+            Some(community) => Some(self.feeds.get(community)?),
+            None => None,
+        };
 
-        let mut search_results;
-        let mut skip_results;
-        let mut take_results;
+        Some(Feed {
+            cache: self,
+            community,
+        })
+    }
+}
+
+// Moves every url under `from` to the same path under `to`
+fn rescope(value: &mut Value, from: &str, to: &str) {
+    match value {
+        Value::String(s) => {
+            if let Some(rest) = s.strip_prefix(from) {
+                *s = format!("{to}{rest}");
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|x| rescope(x, from, to)),
+        Value::Object(map) => map.values_mut().for_each(|x| rescope(x, from, to)),
+        _ => (),
+    }
+}
+
+/// The packages of a single community, pointing into the global package map.
+#[derive(Default)]
+pub struct CommunityFeed {
+    /// What its documents use in place of the global feed's `{base_url}/nuget/v3/`.
+    pub url: String,
+    pub packages: HashSet<PackageKey<'static>>,
+    pub all_packages: Bytes,
+}
+
+/// Either every package, or those of one community.
+#[derive(Clone, Copy)]
+pub struct Feed<'a> {
+    cache: &'a Cache,
+    community: Option<&'a CommunityFeed>,
+}
 
-        if let Some(query) = q.query {
-            let lowercase = query.to_lowercase();
-            search_results =
-                results.filter(move |x| x.items[0].full_name_lower.contains(&lowercase));
-            results = &mut search_results;
+impl<'a> Feed<'a> {
+    /// The package with this id, if the feed has it.
+    pub fn package(&self, key: &PackageKey<'static>) -> Option<&'a NugetPackage> {
+        let package = self.cache.packages.get_key_value(key)?;
+        match self.community {
+            Some(community) if !community.packages.contains(package.0) => None,
+            _ => Some(package.1),
         }
+    }
 
-        if let Some(skip) = q.skip {
-            skip_results = results.skip(skip);
-            results = &mut skip_results;
+    /// Every package of the feed, in no particular order.
+    pub fn packages(&self) -> Box<dyn Iterator<Item = &'a NugetPackage> + 'a> {
+        match self.community {
+            Some(community) => Box::new(
+                community
+                    .packages
+                    .iter()
+                    .filter_map(|key| self.cache.packages.get(key)),
+            ),
+            None => Box::new(self.cache.packages.values()),
         }
+    }
 
-        if let Some(take) = q.take {
-            take_results = results.take(take);
-            results = &mut take_results;
+    /// The search results for an empty query, serialized when the cache was last refreshed.
+    pub fn all_packages(&self) -> Bytes {
+        match self.community {
+            Some(community) => community.all_packages.clone(),
+            None => self.cache.all_packages.clone(),
         }
+    }
+
+    /// Packages are built with the global feed's urls, so a community points them back at itself.
+    pub fn document(&self, document: impl Serialize) -> Value {
+        let mut value = serde_json::to_value(document).unwrap();
+        if let Some(community) = self.community {
+            let global = format!("{}/nuget/v3/", self.cache.base_url);
+            rescope(&mut value, &global, &community.url);
+        }
+        value
+    }
+
+    /// The packages matching `q`, best matches first.
+    pub fn search(&self, q: SearchQuery) -> SearchResult {
+        search(
+            self.packages(),
+            &self.cache.index,
+            &self.cache.package_types,
+            &q,
+        )
+    }
+
+    /// Either the ids starting with `q`, or the versions of package `id`.
+    pub fn autocomplete(&self, q: AutocompleteQuery) -> AutocompleteResult {
+        let filter = VersionFilter::new(q.prerelease, q.semver_level.as_deref());
+        let versions = |pkg: &'a NugetPackage| {
+            pkg.versions
+                .iter()
+                .filter(|x| filter.allows(&x.catalogEntry.parsed))
+                .map(|x| &x.catalogEntry.version)
+        };
+
+        if let Some(id) = q.id {
+            let data = PackageKey::try_from(id)
+                .ok()
+                .and_then(|key| self.package(&key))
+                .map(|pkg| versions(pkg).cloned().collect())
+                .unwrap_or_default();
+            return AutocompleteResult {
+                totalHits: None,
+                data,
+            };
+        }
+
+        let prefix = q.query.unwrap_or_default().trim().to_lowercase();
+        let package_type = q.package_type.as_deref().filter(|x| !x.is_empty());
+        let mut matches: Vec<_> = self
+            .packages()
+            .filter(|pkg| {
+                let name = &pkg.full_name_lower;
+                (name.starts_with(&prefix)
+                    || name
+                        .split_once('-')
+                        .is_some_and(|(_, name)| name.starts_with(&prefix)))
+                    && package_type.is_none_or(|x| {
+                        latest_type(pkg, &filter, &self.cache.package_types)
+                            .is_some_and(|t| t.name().eq_ignore_ascii_case(x))
+                    })
+                    && versions(pkg).next().is_some()
+            })
+            .collect();
+
+        matches.sort_unstable_by(|a, b| {
+            b.downloads
+                .cmp(&a.downloads)
+                .then_with(|| a.full_name_lower.cmp(&b.full_name_lower))
+        });
+
+        AutocompleteResult {
+            totalHits: Some(matches.len()),
+            data: matches
+                .into_iter()
+                .skip(q.skip.unwrap_or(0))
+                .take(q.take.unwrap_or(DEFAULT_AUTOCOMPLETE_TAKE))
+                .map(|pkg| pkg.full_name.clone())
+                .collect(),
+        }
+    }
+}
+
+fn search<'a>(
+    packages: impl Iterator<Item = &'a NugetPackage>,
+    index: &SearchIndex,
+    types: &PackageTypes,
+    q: &SearchQuery,
+) -> SearchResult {
+    let terms = q.query.as_deref().map(Term::parse).unwrap_or_default();
+    let package_type = q
+        .package_type
+        .as_deref()
+        .map(str::trim)
+        .filter(|x| !x.is_empty());
+    let filter = VersionFilter::new(q.prerelease, q.semver_level.as_deref());
+
+    let free_text = terms
+        .iter()
+        .filter(|term| term.scope == Scope::Any)
+        .map(|term| term.value.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let free_text = (!free_text.is_empty()).then_some(free_text);
+    let matches = index.matches(&terms);
+    // Plain queries keep matching anywhere inside an id, like they did before the index
+    let only_free_text = terms.iter().all(|term| term.scope == Scope::Any);
+
+    let packages = packages.filter(|pkg| {
+        let name = &pkg.full_name_lower;
+        let matched = match &matches {
+            Some(matches) => {
+                matches.contains(name.as_str())
+                    || (only_free_text && free_text.as_ref().is_some_and(|x| name.contains(x)))
+            }
+            None => true,
+        };
+        matched
+            && package_type.is_none_or(|x| {
+                latest_type(pkg, &filter, types).is_some_and(|t| t.name().eq_ignore_ascii_case(x))
+            })
+    });
+    let results: Vec<_> = rank(packages, free_text.as_deref())
+        .into_iter()
+        .filter_map(|pkg| SearchItem::new(pkg, &filter, types))
+        .collect();
+
+    SearchResult {
+        totalHits: results.len(),
+        data: results
+            .into_iter()
+            .skip(q.skip.unwrap_or(0))
+            .take(q.take.unwrap_or(usize::MAX))
+            .collect(),
+    }
+}
+
+// The type of the latest version passing the filter, None until that version is converted
+fn latest_type(
+    pkg: &NugetPackage,
+    filter: &VersionFilter,
+    types: &PackageTypes,
+) -> Option<PackageType> {
+    let latest = pkg
+        .versions
+        .iter()
+        .rev()
+        .find(|x| filter.allows(&x.catalogEntry.parsed))?;
+    types.get(&pkg.full_name, &latest.catalogEntry.version)
+}
+
+// Orders packages by relevance, then downloads, then id so results are stable
+fn rank<'a>(
+    packages: impl Iterator<Item = &'a NugetPackage>,
+    query: Option<&str>,
+) -> Vec<&'a NugetPackage> {
+    let terms: Vec<&str> = query.iter().flat_map(|x| x.split_whitespace()).collect();
+
+    let mut results: Vec<_> = packages
+        .map(|pkg| match query {
+            Some(query) => (relevance(pkg, query, &terms), pkg),
+            None => (0, pkg),
+        })
+        .collect();
+
+    results.sort_unstable_by(|(a_score, a), (b_score, b)| {
+        b_score
+            .cmp(a_score)
+            .then(b.downloads.cmp(&a.downloads))
+            .then_with(|| a.full_name_lower.cmp(&b.full_name_lower))
+    });
+
+    results.into_iter().map(|(_, pkg)| pkg).collect()
+}
 
-        let v: Vec<_> = results.map(|x| x.into()).collect();
+fn relevance(pkg: &NugetPackage, query: &str, terms: &[&str]) -> u32 {
+    let (owner, name) = pkg
+        .full_name_lower
+        .split_once('-')
+        .unwrap_or(("", &pkg.full_name_lower));
 
-        SearchResult {
-            totalHits: v.len(),
-            data: v,
+    let mut score = if pkg.full_name_lower == query {
+        1000
+    } else if name == query {
+        800
+    } else if pkg.full_name_lower.starts_with(query) {
+        500
+    } else if name.starts_with(query) {
+        400
+    } else if pkg.full_name_lower.contains(query) {
+        200
+    } else {
+        0
+    };
+
+    for term in terms {
+        if owner == *term {
+            score += 50;
+        }
+        if name.contains(term) {
+            score += 40;
+        }
+        if pkg.description_lower.contains(term) {
+            score += 10;
         }
     }
+
+    score
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub struct SearchQuery {
     #[serde(rename = "q")]
     pub query: Option<String>,
     pub skip: Option<usize>,
     pub take: Option<usize>,
+    pub prerelease: Option<bool>,
+    #[serde(rename = "semVerLevel")]
+    pub semver_level: Option<String>,
+    #[serde(rename = "packageType")]
+    pub package_type: Option<String>,
+}
+
+impl SearchQuery {
+    pub fn is_empty(&self) -> bool {
+        matches!(
+            self,
+            SearchQuery {
+                query: None,
+                skip: None,
+                take: None,
+                prerelease: None,
+                semver_level: None,
+                package_type: None,
+            }
+        )
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct AutocompleteQuery {
+    #[serde(rename = "q")]
+    pub query: Option<String>,
+    pub id: Option<String>,
+    pub skip: Option<usize>,
+    pub take: Option<usize>,
+    pub prerelease: Option<bool>,
+    #[serde(rename = "semVerLevel")]
+    pub semver_level: Option<String>,
+    #[serde(rename = "packageType")]
+    pub package_type: Option<String>,
+}
+
+const DEFAULT_AUTOCOMPLETE_TAKE: usize = 20;
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug)]
+pub struct AutocompleteResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub totalHits: Option<usize>,
+    pub data: Vec<String>,
+}
+
+// Which versions a client is able to see
+struct VersionFilter {
+    prerelease: bool,
+    semver2: bool,
+}
+
+impl VersionFilter {
+    fn new(prerelease: Option<bool>, semver_level: Option<&str>) -> Self {
+        Self {
+            prerelease: prerelease.unwrap_or(false),
+            semver2: semver_level
+                .and_then(|level| level.split('.').next()?.parse::<u32>().ok())
+                .is_some_and(|major| major >= 2),
+        }
+    }
+
+    fn allows(&self, version: &Version) -> bool {
+        (self.prerelease || !version.is_prerelease()) && (self.semver2 || !version.is_semver2())
+    }
 }
 
 #[derive(Deserialize)]
@@ -205,15 +1007,31 @@ pub struct TSCommunityList {
     pub results: Vec<TSCommunity>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct TSPackage {
     pub full_name: String,
+    /// Missing from lists saved before it was kept.
+    #[serde(default)]
+    pub uuid4: String,
     pub package_url: String,
     pub is_deprecated: bool,
+    #[serde(default)]
+    pub categories: Vec<String>,
     pub versions: Vec<TSVersion>,
 }
 
-#[derive(Deserialize)]
+impl TSPackage {
+    /// The highest version, which isn't necessarily the last one uploaded.
+    pub fn latest(&self) -> Option<&TSVersion> {
+        self.versions
+            .iter()
+            .filter_map(|x| Some((x.version_number.parse::<Version>().ok()?, x)))
+            .max_by(|(a, x), (b, y)| a.cmp(b).then_with(|| x.date_created.cmp(&y.date_created)))
+            .map(|(_, x)| x)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct TSVersion {
     pub description: String,
     pub icon: String,
@@ -225,28 +1043,140 @@ pub struct TSVersion {
     pub dependencies: Vec<String>,
 }
 
-#[derive(Serialize)]
+const REGISTRATION_PAGE_SIZE: usize = 64;
+// Packages with more versions than this only link to their registration pages
+const REGISTRATION_INLINE_LIMIT: usize = 128;
+
 pub struct NugetPackage {
-    #[serde(rename = "@id")]
+    /// Registration index url.
     pub id: String,
+    pub full_name: String,
+    pub full_name_lower: String,
+    pub description_lower: String,
+    pub downloads: u64,
+    /// Oldest first, like registration pages.
+    pub versions: Vec<NugetVersion>,
+}
+
+impl NugetPackage {
+    pub fn index(&self) -> RegistrationIndex<'_> {
+        let inline = self.versions.len() <= REGISTRATION_INLINE_LIMIT;
+        let items: Vec<_> = self
+            .versions
+            .chunks(REGISTRATION_PAGE_SIZE)
+            .map(|page| self.page_for(page, inline))
+            .collect();
+
+        RegistrationIndex {
+            id: &self.id,
+            res_type: [
+                "PackageRegistration",
+                "catalog:CatalogRoot",
+                "catalog:Permalink",
+            ],
+            count: items.len(),
+            items,
+        }
+    }
+
+    /// Versions are matched by their normalized form.
+    pub fn page(&self, lower: &str, upper: &str) -> Option<RegistrationPage<'_>> {
+        let (lower, upper) = (lower.parse().ok()?, upper.parse().ok()?);
+        self.versions
+            .chunks(REGISTRATION_PAGE_SIZE)
+            .find(|page| {
+                let (first, last) = bounds(page);
+                first.catalogEntry.parsed == lower && last.catalogEntry.parsed == upper
+            })
+            .map(|page| self.page_for(page, true))
+    }
+
+    pub fn version(&self, version: &str) -> Option<&NugetVersion> {
+        let version: Version = version.parse().ok()?;
+        self.versions
+            .iter()
+            .find(|x| x.catalogEntry.parsed == version)
+    }
+
+    pub fn leaf(&self, version: &str) -> Option<RegistrationLeaf<'_>> {
+        let version = self.version(version)?;
+
+        Some(RegistrationLeaf {
+            id: &version.id,
+            res_type: ["Package", "http://schema.nuget.org/catalog#Permalink"],
+            catalogEntry: &version.catalogEntry,
+            listed: true,
+            packageContent: &version.packageContent,
+            published: &version.catalogEntry.published,
+            registration: &self.id,
+        })
+    }
+
+    fn page_for<'a>(&'a self, page: &'a [NugetVersion], inline: bool) -> RegistrationPage<'a> {
+        let (lower, upper) = bounds(page);
+        let (lower, upper) = (&lower.catalogEntry.version, &upper.catalogEntry.version);
+        // Pages live next to the registration index
+        let id = format!(
+            "{}page/{}/{}.json",
+            self.id.trim_end_matches("index.json"),
+            lower.to_lowercase(),
+            upper.to_lowercase()
+        );
+
+        RegistrationPage {
+            id,
+            res_type: "catalog:CatalogPage",
+            count: page.len(),
+            lower,
+            upper,
+            parent: &self.id,
+            items: inline.then_some(page),
+        }
+    }
+}
+
+// The lowest and highest versions of a page, which is sorted like every version list
+fn bounds(page: &[NugetVersion]) -> (&NugetVersion, &NugetVersion) {
+    (&page[0], &page[page.len() - 1])
+}
+
+#[derive(Serialize)]
+pub struct RegistrationIndex<'a> {
+    #[serde(rename = "@id")]
+    pub id: &'a str,
     #[serde(rename = "@type")]
     pub res_type: [&'static str; 3],
-    pub count: u8,
-    pub items: [NugetPackageInner; 1],
+    pub count: usize,
+    pub items: Vec<RegistrationPage<'a>>,
 }
 
 #[derive(Serialize)]
-pub struct NugetPackageInner {
+pub struct RegistrationPage<'a> {
     #[serde(rename = "@id")]
     pub id: String,
-    #[serde(skip)]
-    pub full_name: String,
-    #[serde(skip)]
-    pub full_name_lower: String,
+    #[serde(rename = "@type")]
+    pub res_type: &'static str,
     pub count: usize,
-    pub lower: String,
-    pub upper: String,
-    pub items: Vec<NugetVersion>,
+    pub lower: &'a str,
+    pub upper: &'a str,
+    pub parent: &'a str,
+    /// Left out when the page has to be fetched on its own.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub items: Option<&'a [NugetVersion]>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize)]
+pub struct RegistrationLeaf<'a> {
+    #[serde(rename = "@id")]
+    pub id: &'a str,
+    #[serde(rename = "@type")]
+    pub res_type: [&'static str; 2],
+    pub catalogEntry: &'a NugetVersionInner,
+    pub listed: bool,
+    pub packageContent: &'a str,
+    pub published: &'a str,
+    pub registration: &'a str,
 }
 
 #[allow(non_snake_case)]
@@ -266,6 +1196,74 @@ pub struct Deprecation {
     pub reasons: [&'static str; 1],
 }
 
+#[allow(non_snake_case)]
+#[derive(Serialize, Clone)]
+pub struct NugetDependencyGroup {
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(rename = "@type")]
+    pub res_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub targetFramework: Option<&'static str>,
+    pub dependencies: Vec<NugetDependency>,
+}
+
+impl NugetDependencyGroup {
+    pub fn new(
+        entry_id: &str,
+        target_framework: Option<&'static str>,
+        dependencies: &[String],
+        base_url: &str,
+    ) -> Self {
+        let id = match target_framework {
+            Some(framework) => format!("{}#dependencygroup/{}", entry_id, framework.to_lowercase()),
+            None => format!("{entry_id}#dependencygroup"),
+        };
+
+        Self {
+            dependencies: dependencies
+                .iter()
+                .filter_map(|dep| NugetDependency::parse(&id, dep, base_url))
+                .collect(),
+            id,
+            res_type: "PackageDependencyGroup",
+            targetFramework: target_framework,
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct NugetDependency {
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(rename = "@type")]
+    pub res_type: &'static str,
+    #[serde(rename = "id")]
+    pub package_id: String,
+    pub range: String,
+    pub registration: String,
+}
+
+impl NugetDependency {
+    /// Thunderstore dependencies look like `Owner-Name-1.2.3`.
+    pub fn parse(group_id: &str, dependency: &str, base_url: &str) -> Option<Self> {
+        let (id, version) = dependency.rsplit_once('-')?;
+        let version: Version = version.parse().ok()?;
+        if id.is_empty() {
+            return None;
+        }
+        let id_lower = id.to_lowercase();
+
+        Some(Self {
+            id: format!("{group_id}/{id_lower}"),
+            res_type: "PackageDependency",
+            registration: format!("{base_url}/nuget/v3/package/{id_lower}/index.json"),
+            package_id: id.to_string(),
+            range: format!("[{version},)"),
+        })
+    }
+}
+
 #[allow(non_snake_case)]
 #[derive(Serialize, Clone)]
 pub struct NugetVersionInner {
@@ -277,82 +1275,100 @@ pub struct NugetVersionInner {
     pub version: String,
     pub packageContent: String,
     pub deprecation: Option<Deprecation>,
+    pub dependencyGroups: Vec<NugetDependencyGroup>,
+    #[serde(skip)]
+    pub(crate) parsed: Version,
     #[serde(skip)]
     pub downloads: u32,
     #[serde(skip)]
     pub download_url: String,
 }
 
-impl From<TSPackage> for NugetPackage {
-    fn from(pkg: TSPackage) -> Self {
-        let base_url = crate::BASE_URL.get().unwrap();
+impl NugetPackage {
+    pub fn new(pkg: &TSPackage, base_url: &str) -> Self {
         let full_name_lower = pkg.full_name.to_lowercase();
         let url = format!(
             "{}/nuget/v3/package/{}/index.json",
             base_url, full_name_lower
         );
 
-        NugetPackage {
-            id: url.clone(),
-            res_type: [
-                "PackageRegistration",
-                "catalog:CatalogRoot",
-                "catalog:Permalink",
-            ],
-            count: 1,
-            items: [NugetPackageInner {
-                id: url.clone(),
-                full_name: pkg.full_name.clone(),
-                full_name_lower: full_name_lower.clone(),
-                count: pkg.versions.len(),
-                lower: pkg.versions.last().unwrap().version_number.clone(),
-                upper: pkg.versions.first().unwrap().version_number.clone(),
-                items: pkg
-                    .versions
-                    .into_iter()
-                    .map(|version| NugetVersion {
-                        id: url.clone(),
-                        packageContent: format!(
-                            "{}/nuget/v3/base/{}/{}/{}.{}.nupkg",
+        let mut versions: Vec<NugetVersion> = pkg
+            .versions
+            .iter()
+            // Anything NuGet couldn't parse is unreachable for clients anyway
+            .filter_map(|version| Some((version, version.version_number.parse().ok()?)))
+            .map(|(version, parsed): (_, Version)| {
+                let normalized = parsed.to_string();
+                let leaf_url = format!(
+                    "{}/nuget/v3/package/{}/{}.json",
+                    base_url,
+                    full_name_lower,
+                    normalized.to_lowercase()
+                );
+                let package_content = format!(
+                    "{}/nuget/v3/base/{}/{}/{}.{}.nupkg",
+                    base_url,
+                    full_name_lower,
+                    normalized.to_lowercase(),
+                    full_name_lower,
+                    normalized.to_lowercase()
+                );
+
+                NugetVersion {
+                    packageContent: package_content.clone(),
+                    catalogEntry: NugetVersionInner {
+                        id: pkg.full_name.clone(),
+                        description: [&format!(
+                            "{}\n\nPackage URL: {}\nWebsite URL: {}\nDepends on:",
+                            version.description, pkg.package_url, version.website_url
+                        )]
+                        .into_iter()
+                        .chain(&version.dependencies)
+                        .map(|x| x.as_str())
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                        iconUrl: version.icon.clone(),
+                        published: version.date_created.clone(),
+                        packageContent: package_content,
+                        dependencyGroups: vec![NugetDependencyGroup::new(
+                            &leaf_url,
+                            None,
+                            &version.dependencies,
                             base_url,
-                            full_name_lower,
-                            version.version_number,
-                            full_name_lower,
-                            version.version_number
-                        ),
-                        catalogEntry: NugetVersionInner {
-                            id: pkg.full_name.clone(),
-                            description: [&format!(
-                                "{}\n\nPackage URL: {}\nWebsite URL: {}\nDepends on:",
-                                version.description, pkg.package_url, version.website_url
-                            )]
-                            .into_iter()
-                            .chain(&version.dependencies)
-                            .map(|x| x.as_str())
-                            .collect::<Vec<_>>()
-                            .join("\n"),
-                            iconUrl: version.icon,
-                            published: version.date_created,
-                            packageContent: format!(
-                                "{}/nuget/v3/base/{}/{}/{}.{}.nupkg",
-                                base_url,
-                                full_name_lower,
-                                version.version_number,
-                                full_name_lower,
-                                version.version_number
-                            ),
-                            version: version.version_number,
-                            downloads: version.downloads,
-                            download_url: version.download_url,
-                            deprecation: pkg.is_deprecated.then(|| Deprecation {
-                                id: format!("{url}#deprecation"),
-                                message: "Deprecated on Thunderstore",
-                                reasons: ["Other"],
-                            }),
-                        },
-                    })
-                    .collect(),
-            }],
+                        )],
+                        version: normalized,
+                        parsed,
+                        downloads: version.downloads,
+                        download_url: version.download_url.clone(),
+                        deprecation: pkg.is_deprecated.then(|| Deprecation {
+                            id: format!("{leaf_url}#deprecation"),
+                            message: "Deprecated on Thunderstore",
+                            reasons: ["Other"],
+                        }),
+                    },
+                    id: leaf_url,
+                }
+            })
+            .collect();
+        // Oldest first; uploads that normalize to the same version keep the last one created
+        versions.sort_by(|a, b| {
+            a.catalogEntry
+                .parsed
+                .cmp(&b.catalogEntry.parsed)
+                .then_with(|| b.catalogEntry.published.cmp(&a.catalogEntry.published))
+        });
+        versions.dedup_by(|a, b| a.catalogEntry.parsed == b.catalogEntry.parsed);
+
+        NugetPackage {
+            id: url,
+            full_name: pkg.full_name.clone(),
+            full_name_lower,
+            description_lower: pkg
+                .latest()
+                .map(|version| version.description.to_lowercase())
+                .unwrap_or_default(),
+            downloads: pkg.versions.iter().map(|v| v.downloads as u64).sum(),
+            versions,
         }
     }
 }
@@ -373,22 +1389,41 @@ pub struct SearchItem {
     pub versions: Vec<SearchVersion>,
     pub iconUrl: String,
     pub registration: String,
+    pub totalDownloads: u64,
+    /// Empty until the latest version has been converted and its type is known.
+    pub packageTypes: Vec<SearchPackageType>,
 }
 
-impl From<&NugetPackage> for SearchItem {
-    fn from(pkg: &NugetPackage) -> Self {
-        Self {
-            id: pkg.items[0].full_name.clone(),
-            version: pkg.items[0].upper.clone(),
-            description: pkg.items[0].items[0].catalogEntry.description.clone(),
-            versions: pkg.items[0].items.iter().map(|x| x.into()).collect(),
-            iconUrl: pkg.items[0].items[0].catalogEntry.iconUrl.clone(),
-            registration: format!(
-                "{}/nuget/v3/package/{}/index.json",
-                crate::BASE_URL.get().unwrap(),
-                pkg.items[0].full_name
-            ),
-        }
+#[derive(Serialize, Debug)]
+pub struct SearchPackageType {
+    pub name: &'static str,
+}
+
+impl SearchItem {
+    // None if none of the package's versions pass the filter
+    fn new(pkg: &NugetPackage, filter: &VersionFilter, types: &PackageTypes) -> Option<Self> {
+        let versions: Vec<_> = pkg
+            .versions
+            .iter()
+            .filter(|x| filter.allows(&x.catalogEntry.parsed))
+            .collect();
+        let latest = versions.last()?;
+        let package_types = types
+            .get(&pkg.full_name, &latest.catalogEntry.version)
+            .map(|x| SearchPackageType { name: x.name() })
+            .into_iter()
+            .collect();
+
+        Some(Self {
+            id: pkg.full_name.clone(),
+            version: latest.catalogEntry.version.clone(),
+            description: latest.catalogEntry.description.clone(),
+            iconUrl: latest.catalogEntry.iconUrl.clone(),
+            versions: versions.into_iter().map(|x| x.into()).collect(),
+            registration: pkg.id.clone(),
+            totalDownloads: pkg.downloads,
+            packageTypes: package_types,
+        })
     }
 }
 
@@ -403,9 +1438,75 @@ pub struct SearchVersion {
 impl From<&NugetVersion> for SearchVersion {
     fn from(ver: &NugetVersion) -> Self {
         Self {
-            id: format!("{}#{}", ver.id, ver.catalogEntry.version),
+            id: ver.id.clone(),
             version: ver.catalogEntry.version.clone(),
             downloads: ver.catalogEntry.downloads,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(version_number: &str, date_created: &str) -> TSVersion {
+        TSVersion {
+            description: format!("Uploaded {date_created}"),
+            icon: String::new(),
+            version_number: version_number.to_string(),
+            download_url: format!("https://example.com/{version_number}"),
+            downloads: 0,
+            date_created: date_created.to_string(),
+            website_url: String::new(),
+            dependencies: vec![],
+        }
+    }
+
+    fn package(versions: Vec<TSVersion>) -> NugetPackage {
+        let pkg = TSPackage {
+            full_name: "Author-Mod".to_string(),
+            uuid4: String::new(),
+            package_url: String::new(),
+            is_deprecated: false,
+            categories: vec![],
+            versions,
+        };
+        NugetPackage::new(&pkg, "http://localhost")
+    }
+
+    #[test]
+    fn duplicate_versions_keep_the_last_created() {
+        for versions in [
+            vec![
+                version("1.0", "2024-01-01T00:00:00Z"),
+                version("1.0.0", "2024-02-01T00:00:00Z"),
+                version("0.9.0", "2023-12-01T00:00:00Z"),
+            ],
+            vec![
+                version("0.9.0", "2023-12-01T00:00:00Z"),
+                version("1.0.0", "2024-02-01T00:00:00Z"),
+                version("1.0", "2024-01-01T00:00:00Z"),
+            ],
+        ] {
+            let pkg = package(versions);
+            let kept: Vec<_> = pkg
+                .versions
+                .iter()
+                .map(|x| {
+                    (
+                        x.catalogEntry.version.as_str(),
+                        x.catalogEntry.published.as_str(),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                kept,
+                [
+                    ("0.9.0", "2023-12-01T00:00:00Z"),
+                    ("1.0.0", "2024-02-01T00:00:00Z"),
+                ]
+            );
+            assert_eq!(pkg.description_lower, "uploaded 2024-02-01t00:00:00z");
+        }
+    }
+}
@@ -1,93 +1,589 @@
 use axum::body::Body;
+use axum::http::StatusCode;
+use futures::future::{BoxFuture, Shared};
+use futures::{FutureExt, StreamExt};
 use std::{
-    io::Write,
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet},
+    io::{Cursor, Read, Write},
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+use crate::assembly::target_framework;
+use crate::layout::{Layout, Target};
 use crate::metadata::NugetVersion;
+use crate::package_type::{PackageType, PackageTypes};
+use crate::symbols;
+use crate::upstream::{Upstream, UpstreamError};
+use thiserror::Error;
 use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
 use tokio_util::io::ReaderStream;
+use zip::result::ZipError;
 use zip::write::SimpleFileOptions;
 use zip::{ZipArchive, ZipWriter};
 
+pub const NUPKG_DIR: &str = "nupkgs";
+pub const DEFAULT_FRAMEWORK: &str = "netstandard2.0";
+// Files carried along with an assembly, by the suffix replacing `.dll`
+const COMPANIONS: [&str; 4] = [".xml", ".pdb", ".dll.mdb", ".mdb"];
+
+#[derive(Error, Debug)]
+pub enum NupkgError {
+    #[error("Failed to download package; {0}")]
+    Download(#[from] UpstreamError),
+    #[error("Package archive is unreadable; {0}")]
+    Zip(#[from] ZipError),
+    #[error("Failed to write package; {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Both {first} and {second} would be packed as {path}")]
+    Collision {
+        path: String,
+        first: String,
+        second: String,
+    },
+    #[error("Package conversion panicked; {0}")]
+    Panicked(#[from] tokio::task::JoinError),
+    /// Every request waiting on a conversion gets the same error.
+    #[error(transparent)]
+    Shared(Arc<NupkgError>),
+}
+
+impl NupkgError {
+    /// Anything wrong with the Thunderstore archive is upstream's fault, not ours.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Download(_) | Self::Zip(_) | Self::Collision { .. } => StatusCode::BAD_GATEWAY,
+            Self::Io(_) | Self::Panicked(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Shared(err) => err.status(),
+        }
+    }
+}
+
+/// How Thunderstore archives are turned into nupkgs.
+pub struct ConvertOptions {
+    /// Where files from an archive end up in the nupkg.
+    pub layout: Layout,
+    /// The framework assemblies are packed under when it can't be read from them.
+    pub default_framework: String,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            layout: Layout::default(),
+            default_framework: DEFAULT_FRAMEWORK.to_string(),
+        }
+    }
+}
+
+type Conversion = Shared<BoxFuture<'static, Result<(), Arc<NupkgError>>>>;
+
+// Takes a conversion out of its store's map once it's done, even if it panicked
+struct Converting {
+    store: Arc<NupkgStore>,
+    path: PathBuf,
+}
+
+impl Drop for Converting {
+    fn drop(&mut self) {
+        self.store.conversions.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// The converted packages on disk, evicting the least recently used ones beyond a size budget.
+pub struct NupkgStore {
+    dir: PathBuf,
+    budget: Option<u64>,
+    files: Mutex<StoredFiles>,
+    // Conversions in progress by nupkg path, so concurrent requests share one download
+    conversions: Mutex<HashMap<PathBuf, Conversion>>,
+}
+
+#[derive(Default)]
+struct StoredFiles {
+    entries: HashMap<PathBuf, StoredFile>,
+    size: u64,
+}
+
+struct StoredFile {
+    size: u64,
+    last_access: SystemTime,
+    // Requests streaming the file, which must not have it deleted under them
+    readers: usize,
+}
+
+impl NupkgStore {
+    /// Picks up the packages already converted under `data_dir`, keeping at most `budget` bytes
+    /// of them, or all of them if None.
+    pub fn open(data_dir: &Path, budget: Option<u64>) -> Self {
+        let dir = data_dir.join(NUPKG_DIR);
+        let mut files = StoredFiles::default();
+
+        for entry in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            match path.extension().and_then(|x| x.to_str()) {
+                // Conversions interrupted by a restart leave their temp files behind
+                Some("tmp") => {
+                    let _ = std::fs::remove_file(&path);
+                }
+                Some("nupkg") => {
+                    let Ok(metadata) = entry.metadata() else {
+                        continue;
+                    };
+                    // Access times aren't reliable, so go by when each package was converted
+                    files.insert(
+                        path,
+                        StoredFile {
+                            size: metadata.len(),
+                            last_access: metadata.modified().unwrap_or(UNIX_EPOCH),
+                            readers: 0,
+                        },
+                    );
+                }
+                _ => (),
+            }
+        }
+
+        let store = Self {
+            dir,
+            budget,
+            files: Mutex::new(files),
+            conversions: Default::default(),
+        };
+        store.evict(&mut store.files.lock().unwrap());
+        store
+    }
+
+    /// Bytes of converted packages currently on disk.
+    pub fn size(&self) -> u64 {
+        self.files.lock().unwrap().size
+    }
+
+    // Keeps the package from being evicted until the lease is dropped, None if it isn't on disk
+    fn lease(self: &Arc<Self>, path: &Path) -> Option<Lease> {
+        let mut files = self.files.lock().unwrap();
+        if !files.entries.contains_key(path) {
+            let size = std::fs::metadata(path).ok()?.len();
+            files.insert(
+                path.to_path_buf(),
+                StoredFile {
+                    size,
+                    last_access: SystemTime::now(),
+                    readers: 0,
+                },
+            );
+        }
+
+        let file = files.entries.get_mut(path).unwrap();
+        file.readers += 1;
+        file.last_access = SystemTime::now();
+        self.evict(&mut files);
+
+        Some(Lease {
+            store: self.clone(),
+            path: path.to_path_buf(),
+        })
+    }
+
+    // Called with the store locked; leased files are skipped, even if that leaves it over budget
+    fn evict(&self, files: &mut StoredFiles) {
+        let Some(budget) = self.budget else {
+            return;
+        };
+        if files.size <= budget {
+            return;
+        }
+
+        let mut candidates: Vec<_> = files
+            .entries
+            .iter()
+            .filter(|(_, file)| file.readers == 0)
+            .map(|(path, file)| (file.last_access, path.clone()))
+            .collect();
+        candidates.sort_unstable();
+
+        for (_, path) in candidates {
+            if files.size <= budget {
+                break;
+            }
+            match std::fs::remove_file(&path) {
+                Ok(()) => (),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                Err(err) => {
+                    eprintln!("Failed to evict {}: {err}", path.display());
+                    continue;
+                }
+            }
+            files.remove(&path);
+        }
+    }
+}
+
+impl StoredFiles {
+    // An existing entry is kept as is, since a request may already be holding a lease on it
+    fn insert(&mut self, path: PathBuf, file: StoredFile) {
+        if let Entry::Vacant(entry) = self.entries.entry(path) {
+            self.size += file.size;
+            entry.insert(file);
+        }
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(old) = self.entries.remove(path) {
+            self.size -= old.size;
+        }
+    }
+}
+
+struct Lease {
+    store: Arc<NupkgStore>,
+    path: PathBuf,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut files = self.store.files.lock().unwrap();
+        if let Some(file) = files.entries.get_mut(&self.path) {
+            debug_assert!(file.readers > 0, "Lease dropped without a reader");
+            file.readers = file.readers.saturating_sub(1);
+        }
+        // Anything skipped while this was streaming can go now
+        self.store.evict(&mut files);
+    }
+}
+
+/// A converted package on disk, ready to be streamed to a client. It won't be evicted from the
+/// store while this or its body is alive.
 pub struct Nupkg {
     path: PathBuf,
+    lease: Lease,
 }
 
 impl Nupkg {
-    pub async fn get_for_pkg(pkg: &NugetVersion) -> Result<Self, reqwest::Error> {
+    /// Converts the Thunderstore archive of `pkg` into a nupkg in `store`, unless that was
+    /// already done. Concurrent calls for the same version share a single conversion.
+    pub async fn get_for_pkg(
+        pkg: &NugetVersion,
+        upstream: Arc<dyn Upstream>,
+        options: Arc<ConvertOptions>,
+        store: Arc<NupkgStore>,
+        types: Arc<PackageTypes>,
+        data_dir: &Path,
+    ) -> Result<Self, NupkgError> {
         let name = format!("{}.{}", pkg.catalogEntry.id, pkg.catalogEntry.version);
-        let init_path = Path::new("nupkgs");
-        let path = init_path.join(name.clone() + ".nupkg");
-        let zip_path = init_path.join(name + ".zip");
-
-        if !path.exists() {
-            let ts_bytes = reqwest::get(&pkg.catalogEntry.download_url)
-                .await?
-                .bytes()
-                .await?;
-
-            let mut zip_file = tokio::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(&zip_path)
-                .await
-                .unwrap();
-            zip_file.write_all(&ts_bytes).await.unwrap();
-            let mut zip = ZipArchive::new(zip_file.into_std().await).unwrap();
-
-            let mut nuget = ZipWriter::new(
-                std::fs::OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(&path)
-                    .unwrap(),
-            );
-            let names: Vec<String> = zip
-                .file_names()
-                .filter(|x| x.ends_with(".dll"))
-                .map(|x| x.to_string())
-                .collect();
-            for file in names {
-                nuget
-                    .start_file_from_path(
-                        Path::new("lib")
-                            .join("netstandard2.0")
-                            .join(Path::new(&file).file_name().unwrap()),
-                        SimpleFileOptions::default(),
-                    )
-                    .unwrap();
-                let mut inner_file = zip.by_name(&file).unwrap();
-                std::io::copy(&mut inner_file, &mut nuget).unwrap();
+        let path = store.dir.join(name + ".nupkg");
+
+        if let Some(lease) = store.lease(&path) {
+            return Ok(Self { path, lease });
+        }
+
+        let conversion = {
+            let mut conversions = store.conversions.lock().unwrap();
+            // A conversion renames its package before leaving the map, so check again while locked
+            if let Some(lease) = store.lease(&path) {
+                return Ok(Self { path, lease });
             }
+            conversions
+                .entry(path.clone())
+                .or_insert_with(|| {
+                    let pkg = pkg.clone();
+                    let path = path.clone();
+                    let store = store.clone();
+                    let data_dir = data_dir.to_path_buf();
+                    // Spawned so it finishes even if the request that started it goes away
+                    let task = tokio::spawn(async move {
+                        let _converting = Converting {
+                            store: store.clone(),
+                            path: path.clone(),
+                        };
+                        Self::convert(&pkg, &path, &*upstream, options, &store, &types, &data_dir)
+                            .await
+                            .map_err(Arc::new)
+                    });
+                    async move { task.await.map_err(|err| Arc::new(err.into()))? }
+                        .boxed()
+                        .shared()
+                })
+                .clone()
+        };
+        conversion.await.map_err(NupkgError::Shared)?;
+
+        // Only a package bigger than the whole budget is evicted before anyone leases it
+        let lease = store
+            .lease(&path)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+        Ok(Self { path, lease })
+    }
+
+    async fn convert(
+        pkg: &NugetVersion,
+        path: &Path,
+        upstream: &dyn Upstream,
+        options: Arc<ConvertOptions>,
+        store: &NupkgStore,
+        types: &PackageTypes,
+        data_dir: &Path,
+    ) -> Result<(), NupkgError> {
+        let name = format!("{}.{}", pkg.catalogEntry.id, pkg.catalogEntry.version);
+        let dir = path.parent().unwrap();
+        let ts_bytes = upstream.download(&pkg.catalogEntry.download_url).await?;
 
-            nuget
-                .start_file(
-                    format!("{}.nuspec", pkg.catalogEntry.id),
-                    SimpleFileOptions::default(),
-                )
-                .unwrap();
+        // Only complete packages ever show up under the final name
+        tokio::fs::create_dir_all(dir).await?;
+        let temp_path = dir.join(format!("{name}.{}.tmp", uuid::Uuid::new_v4().simple()));
+        // Decompressing and packing a big mod shouldn't hold up other requests on this worker
+        let task = {
+            let pkg = pkg.clone();
+            let temp_path = temp_path.clone();
+            tokio::task::spawn_blocking(move || {
+                let pdbs = archive_pdbs(&ts_bytes);
+                (pdbs, write_nupkg(&pkg, &ts_bytes, &options, &temp_path))
+            })
+        };
+        let (pdbs, written) = task.await.unwrap_or_else(|err| (vec![], Err(err.into())));
 
-            write!(
-                nuget,
-                include_str!("template.nuspec"),
-                pkg.catalogEntry.id, pkg.catalogEntry.version, pkg.catalogEntry.description
-            )
-            .unwrap();
+        // Indexed while the archive is at hand, even if it can't be packed
+        for (name, bytes) in pdbs {
+            let file = name.rsplit('/').next().unwrap();
+            if let Err(err) = symbols::store(data_dir, file, &bytes).await {
+                eprintln!("Failed to index symbols for {name}: {err}");
+            }
+        }
 
-            drop(zip);
-            tokio::fs::remove_file(zip_path).await.unwrap();
+        let package_type = match written {
+            Ok(package_type) => package_type,
+            Err(err) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                return Err(err);
+            }
+        };
+        let size = tokio::fs::metadata(&temp_path).await?.len();
+        if let Err(err) = tokio::fs::rename(&temp_path, path).await {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(err.into());
         }
+        // Not evicted yet, so the requests waiting on it get a chance to lease it. A request that
+        // found the renamed file first has already added it
+        store.files.lock().unwrap().insert(
+            path.to_path_buf(),
+            StoredFile {
+                size,
+                last_access: SystemTime::now(),
+                readers: 0,
+            },
+        );
+
+        let (id, version) = (&pkg.catalogEntry.id, &pkg.catalogEntry.version);
+        if let Err(err) = types.record(id, version, package_type) {
+            eprintln!("Failed to save the package type of {name}: {err}");
+        }
+
+        Ok(())
+    }
 
-        Ok(Self { path })
+    /// Streams the nupkg from disk.
+    pub async fn get_body(self) -> Result<Body, NupkgError> {
+        let file = File::open(&self.path).await?;
+        let lease = self.lease;
+        // The body holds on to the lease until it's done streaming
+        let stream = ReaderStream::new(file).map(move |chunk| {
+            let _lease = &lease;
+            chunk
+        });
+        Ok(Body::from_stream(stream))
     }
+}
+
+// Every PDB in a Thunderstore archive by its path, whatever the layout does with it
+fn archive_pdbs(ts_bytes: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let Ok(mut zip) = ZipArchive::new(Cursor::new(ts_bytes)) else {
+        return vec![];
+    };
+    let names: Vec<String> = zip
+        .file_names()
+        .filter(|x| x.to_lowercase().ends_with(".pdb"))
+        .map(|x| x.to_string())
+        .collect();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let bytes = read_entry(&mut zip, &name).ok()?;
+            Some((name.replace('\\', "/"), bytes))
+        })
+        .collect()
+}
+
+// Writes the nupkg for a Thunderstore archive to `path`, returning what kind of package it is
+fn write_nupkg(
+    pkg: &NugetVersion,
+    ts_bytes: &[u8],
+    options: &ConvertOptions,
+    path: &Path,
+) -> Result<PackageType, NupkgError> {
+    let mut zip = ZipArchive::new(Cursor::new(ts_bytes))?;
+
+    let entries: HashMap<String, String> = zip
+        .file_names()
+        .map(|x| (x.replace('\\', "/").to_lowercase(), x.to_string()))
+        .collect();
+    let names: Vec<String> = zip
+        .file_names()
+        .filter(|x| !x.ends_with('/'))
+        .map(|x| x.to_string())
+        .collect();
+    let is_assembly = |name: &str| name.to_lowercase().ends_with(".dll");
+    // Docs and symbols go wherever their assembly goes, never on their own
+    let companions: HashSet<String> = names
+        .iter()
+        .filter(|x| is_assembly(x))
+        .flat_map(|x| {
+            let stem = x.replace('\\', "/").to_lowercase();
+            let stem = stem[..stem.len() - ".dll".len()].to_string();
+            COMPANIONS
+                .iter()
+                .map(move |suffix| format!("{stem}{suffix}"))
+        })
+        .collect();
 
-    pub async fn get_body(&self) -> Body {
-        Body::from_stream(ReaderStream::new(File::open(&self.path).await.unwrap()))
+    let package_type = if names.iter().any(|x| is_assembly(x)) {
+        PackageType::Plugin
+    } else {
+        PackageType::Content
+    };
+    let mut frameworks = BTreeSet::new();
+    let mut files = vec![];
+    for file in names {
+        let normalized = file.replace('\\', "/");
+        let assembly = is_assembly(&normalized);
+        if !assembly && companions.contains(&normalized.to_lowercase()) {
+            continue;
+        }
+        let (destination, rest) = match options.layout.map(&normalized) {
+            None | Some((Target::Skip, _)) => continue,
+            // lib/ only holds assemblies, along with their companions
+            Some((Target::Lib, _)) if !assembly => continue,
+            Some(mapping) => mapping,
+        };
+
+        let bytes = read_entry(&mut zip, &file)?;
+        let destination = match destination {
+            Target::Lib => {
+                let framework =
+                    target_framework(&bytes).unwrap_or_else(|| options.default_framework.clone());
+                let destination = format!("lib/{framework}/{rest}");
+                frameworks.insert(framework);
+                destination
+            }
+            Target::Folder(folder) => format!("{folder}/{rest}"),
+            Target::Skip => unreachable!(),
+        };
+
+        if assembly {
+            let stem = &normalized[..normalized.len() - ".dll".len()];
+            let dest_stem = &destination[..destination.len() - ".dll".len()];
+            for suffix in COMPANIONS {
+                let source = format!("{stem}{suffix}").to_lowercase();
+                if let Some(original) = entries.get(&source) {
+                    let bytes = read_entry(&mut zip, original)?;
+                    files.push((format!("{dest_stem}{suffix}"), original.clone(), bytes));
+                }
+            }
+        }
+
+        files.push((destination, file, bytes));
+    }
+
+    let mut placed = HashMap::new();
+    for (destination, source, _) in &files {
+        if let Some(existing) = placed.insert(destination.to_lowercase(), source) {
+            return Err(NupkgError::Collision {
+                path: destination.clone(),
+                first: existing.clone(),
+                second: source.clone(),
+            });
+        }
+    }
+
+    let mut nuget = ZipWriter::new(std::fs::File::create(path)?);
+    for (destination, _, bytes) in files {
+        nuget.start_file(destination.as_str(), SimpleFileOptions::default())?;
+        nuget.write_all(&bytes)?;
+    }
+
+    nuget.start_file(
+        format!("{}.nuspec", pkg.catalogEntry.id),
+        SimpleFileOptions::default(),
+    )?;
+
+    let dependencies: Vec<_> = pkg
+        .catalogEntry
+        .dependencyGroups
+        .iter()
+        .flat_map(|group| &group.dependencies)
+        .collect();
+    let mut groups = String::new();
+    let package_type = if frameworks.is_empty() {
+        package_type
+    } else {
+        PackageType::Dependency
+    };
+    let frameworks: Vec<_> = if frameworks.is_empty() {
+        vec![None]
+    } else {
+        frameworks.iter().map(Some).collect()
+    };
+    for framework in frameworks {
+        match framework {
+            Some(framework) => {
+                groups += &format!("      <group targetFramework=\"{framework}\">\n")
+            }
+            None => groups += "      <group>\n",
+        }
+        for dep in &dependencies {
+            groups += &format!(
+                "        <dependency id=\"{}\" version=\"{}\" />\n",
+                xml_escape(&dep.package_id),
+                xml_escape(&dep.range)
+            );
+        }
+        groups += "      </group>\n";
+    }
+
+    write!(
+        nuget,
+        include_str!("template.nuspec"),
+        xml_escape(&pkg.catalogEntry.id),
+        xml_escape(&pkg.catalogEntry.version),
+        xml_escape(&pkg.catalogEntry.description),
+        groups
+    )?;
+    nuget.finish()?.sync_all()?;
+
+    Ok(package_type)
+}
+
+// Entries are decompressed as they're read, so failing here means the archive is corrupt
+fn read_entry(zip: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Vec<u8>, NupkgError> {
+    let mut bytes = vec![];
+    zip.by_name(name)?
+        .read_to_end(&mut bytes)
+        .map_err(|err| NupkgError::Zip(err.into()))?;
+    Ok(bytes)
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
     }
+    out
 }
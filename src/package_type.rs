@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+pub const PACKAGE_TYPES_FILE: &str = "package-types.json";
+
+/// What a version's archive holds, as found when it was converted.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PackageType {
+    /// Has assemblies the layout packs under `lib/`, so projects can reference it.
+    Dependency,
+    /// Has assemblies, but none under `lib/`, like a patcher only the mod loader uses.
+    Plugin,
+    /// Has no assemblies at all, like a modpack or an asset pack.
+    Content,
+}
+
+impl PackageType {
+    /// The name used for the `packageType` search parameter and in search results.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Dependency => "Dependency",
+            Self::Plugin => "Plugin",
+            Self::Content => "Content",
+        }
+    }
+}
+
+/// The type of every version converted so far, kept on disk so it outlives its nupkg.
+///
+/// Archives are only fetched when a version is first downloaded, so other versions have no type.
+pub struct PackageTypes {
+    path: PathBuf,
+    // By lowercased `{id}/{normalized version}`
+    types: RwLock<HashMap<String, PackageType>>,
+}
+
+impl PackageTypes {
+    /// Reads the types recorded under `data_dir`. An unreadable file starts over, since every
+    /// type is found again on the next conversion of its version.
+    pub fn load(data_dir: &Path) -> Self {
+        let path = data_dir.join(PACKAGE_TYPES_FILE);
+        let types = match std::fs::File::open(&path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file)).unwrap_or_else(|err| {
+                eprintln!("Ignoring unreadable package types; {err}");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        Self {
+            path,
+            types: RwLock::new(types),
+        }
+    }
+
+    pub fn get(&self, id: &str, version: &str) -> Option<PackageType> {
+        self.types.read().unwrap().get(&key(id, version)).copied()
+    }
+
+    /// Records the type of a converted version, saving it if it's new.
+    pub fn record(
+        &self,
+        id: &str,
+        version: &str,
+        package_type: PackageType,
+    ) -> std::io::Result<()> {
+        let mut types = self.types.write().unwrap();
+        if types.insert(key(id, version), package_type) == Some(package_type) {
+            return Ok(());
+        }
+
+        // Still locked, so saves don't race each other for the temp file
+        let temp_path = self.path.with_extension("tmp");
+        let mut file = BufWriter::new(std::fs::File::create(&temp_path)?);
+        serde_json::to_writer(&mut file, &*types)?;
+        file.flush()?;
+        drop(file);
+        std::fs::rename(temp_path, &self.path)
+    }
+}
+
+fn key(id: &str, version: &str) -> String {
+    format!("{id}/{version}").to_lowercase()
+}
@@ -0,0 +1,59 @@
+use axum::body::Body;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use crate::assembly::portable_pdb_id;
+
+// Symbols are indexed when a version's archive is first fetched, so only versions someone has
+// downloaded have any. They're small and debuggers need them long after the download, so evicting
+// the nupkg keeps them
+pub const SYMBOLS_DIR: &str = "symbols";
+
+// Symbols are stored by their SSQP key, `<file>/<id>/<file>`, all lowercase
+fn key_path(data_dir: &Path, file: &str, id: &str) -> Option<PathBuf> {
+    let valid = |x: &str| !x.is_empty() && x != "." && x != ".." && !x.contains(['/', '\\', '\0']);
+    if !valid(file) || !valid(id) {
+        return None;
+    }
+
+    let file = file.to_lowercase();
+    Some(
+        data_dir
+            .join(SYMBOLS_DIR)
+            .join(&file)
+            .join(id.to_lowercase())
+            .join(file),
+    )
+}
+
+pub async fn store(data_dir: &Path, file: &str, pdb: &[u8]) -> std::io::Result<()> {
+    let Some(path) = portable_pdb_id(pdb).and_then(|id| key_path(data_dir, file, &id)) else {
+        return Ok(());
+    };
+    if tokio::fs::try_exists(&path).await? {
+        return Ok(());
+    }
+
+    // Only complete symbols ever show up under the final name
+    let dir = path.parent().unwrap();
+    tokio::fs::create_dir_all(dir).await?;
+    let temp_path = dir.join(format!("{}.tmp", uuid::Uuid::new_v4().simple()));
+    let result = async {
+        let mut temp = File::create(&temp_path).await?;
+        temp.write_all(pdb).await?;
+        temp.sync_all().await?;
+        tokio::fs::rename(&temp_path, &path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    result
+}
+
+pub async fn get(data_dir: &Path, file: &str, id: &str) -> Option<Body> {
+    let file = File::open(key_path(data_dir, file, id)?).await.ok()?;
+    Some(Body::from_stream(ReaderStream::new(file)))
+}
@@ -0,0 +1,422 @@
+use axum::body::Bytes;
+use flate2::read::GzDecoder;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use reqwest::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use thiserror::Error;
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+
+use crate::metadata::{TSCommunityList, TSPackage, Validators};
+
+pub const DEFAULT_UPSTREAM: &str = "https://thunderstore.io";
+// Body hashes are saved in the snapshot, so they must not change between builds
+const BODY_HASH_SEED: u64 = 0;
+
+#[derive(Error, Debug)]
+pub enum UpstreamError {
+    #[error("Request failed; {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Package list is malformed; {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Package list reported unchanged, but there's no previous list")]
+    UnknownUnchanged,
+    #[error("Package index is malformed; {0}")]
+    Index(std::io::Error),
+    #[error("Upstream doesn't support this")]
+    Unsupported,
+    /// Anything an upstream not reached over HTTP runs into.
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// A community's package list as fetched from upstream.
+pub struct FetchedCommunity {
+    /// None when upstream reports the list unchanged since the last fetch.
+    pub packages: Option<Vec<TSPackage>>,
+    pub validators: Validators,
+}
+
+/// Where package lists and archives come from.
+pub trait Upstream: Send + Sync {
+    /// The identifiers of every community.
+    fn communities(&self) -> BoxFuture<'_, Result<Vec<String>, UpstreamError>>;
+
+    /// A community's whole package list, unless it's unchanged since `validators` were taken.
+    fn packages<'a>(
+        &'a self,
+        community: &'a str,
+        validators: Option<Validators>,
+    ) -> BoxFuture<'a, Result<FetchedCommunity, UpstreamError>>;
+
+    /// The archive at a version's download url.
+    fn download<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Bytes, UpstreamError>>;
+
+    /// Every package version across communities, to tell which packages changed without
+    /// refetching whole lists. None if upstream has no such index.
+    fn package_index(
+        &self,
+        _validators: Option<Validators>,
+    ) -> BoxFuture<'_, Result<Option<FetchedIndex>, UpstreamError>> {
+        async { Ok(None) }.boxed()
+    }
+
+    /// The communities listing a package, for packages new to the index.
+    fn listings<'a>(
+        &'a self,
+        _full_name: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<String>, UpstreamError>> {
+        async { Err(UpstreamError::Unsupported) }.boxed()
+    }
+
+    /// A package of a community's list as it is now, None if the community no longer lists it.
+    fn package<'a>(
+        &'a self,
+        _community: &'a str,
+        _package: &'a TSPackage,
+    ) -> BoxFuture<'a, Result<Option<TSPackage>, UpstreamError>> {
+        async { Err(UpstreamError::Unsupported) }.boxed()
+    }
+}
+
+/// What upstream's package index held at a refresh.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct PackageIndex {
+    /// A hash of each package's version numbers, by full name.
+    pub packages: HashMap<String, u64>,
+    pub validators: Validators,
+}
+
+impl PackageIndex {
+    /// Packages whose versions changed since `previous`, including removed ones, and packages
+    /// `previous` didn't have yet.
+    pub fn changes<'a>(&'a self, previous: &'a PackageIndex) -> (HashSet<&'a str>, Vec<&'a str>) {
+        let changed = previous
+            .packages
+            .iter()
+            .filter(|(name, hash)| self.packages.get(*name) != Some(hash))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        let added = self
+            .packages
+            .keys()
+            .filter(|name| !previous.packages.contains_key(*name))
+            .map(|name| name.as_str())
+            .collect();
+        (changed, added)
+    }
+}
+
+/// A package index as fetched from upstream.
+pub struct FetchedIndex {
+    /// None when upstream reports the index unchanged since the last fetch.
+    pub packages: Option<HashMap<String, u64>>,
+    pub validators: Validators,
+}
+
+/// A Thunderstore instance, thunderstore.io unless configured otherwise.
+pub struct Thunderstore {
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl Thunderstore {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl Default for Thunderstore {
+    fn default() -> Self {
+        Self::new(DEFAULT_UPSTREAM)
+    }
+}
+
+impl Upstream for Thunderstore {
+    fn communities(&self) -> BoxFuture<'_, Result<Vec<String>, UpstreamError>> {
+        async move {
+            let mut next_option = Some(format!("{}/api/experimental/community/", self.base_url));
+            let mut communities = vec![];
+
+            while let Some(next) = next_option {
+                let list = self
+                    .client
+                    .get(next)
+                    .send()
+                    .await?
+                    .json::<TSCommunityList>()
+                    .await?;
+                communities.extend(list.results.into_iter().map(|x| x.identifier));
+                next_option = list.pagination.next_link;
+            }
+
+            Ok(communities)
+        }
+        .boxed()
+    }
+
+    fn packages<'a>(
+        &'a self,
+        community: &'a str,
+        validators: Option<Validators>,
+    ) -> BoxFuture<'a, Result<FetchedCommunity, UpstreamError>> {
+        async move {
+            let url = format!("{}/c/{community}/api/v1/package/", self.base_url);
+            let (body, validators) = self.get_if_changed(url, validators).await?;
+
+            Ok(FetchedCommunity {
+                packages: body.map(|x| serde_json::from_slice(&x)).transpose()?,
+                validators,
+            })
+        }
+        .boxed()
+    }
+
+    fn download<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Bytes, UpstreamError>> {
+        async move {
+            Ok(self
+                .client
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?)
+        }
+        .boxed()
+    }
+
+    fn package_index(
+        &self,
+        validators: Option<Validators>,
+    ) -> BoxFuture<'_, Result<Option<FetchedIndex>, UpstreamError>> {
+        async move {
+            let url = format!("{}/api/experimental/package-index/", self.base_url);
+            let (body, validators) = match self.get_if_changed(url, validators).await {
+                Ok(fetched) => fetched,
+                // Not every Thunderstore instance has it
+                Err(UpstreamError::Request(err)) if err.status() == Some(StatusCode::NOT_FOUND) => {
+                    return Ok(None)
+                }
+                Err(err) => return Err(err),
+            };
+
+            Ok(Some(FetchedIndex {
+                packages: body.map(|x| parse_index(&x)).transpose()?,
+                validators,
+            }))
+        }
+        .boxed()
+    }
+
+    fn listings<'a>(
+        &'a self,
+        full_name: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<String>, UpstreamError>> {
+        #[derive(Deserialize)]
+        struct Package {
+            community_listings: Vec<Listing>,
+        }
+        #[derive(Deserialize)]
+        struct Listing {
+            community: String,
+        }
+
+        async move {
+            // Package names can't contain dashes, but team names can
+            let (namespace, name) = full_name.rsplit_once('-').unwrap_or(("", full_name));
+            let url = format!(
+                "{}/api/experimental/package/{namespace}/{name}/",
+                self.base_url
+            );
+            let response = self.client.get(url).send().await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(vec![]);
+            }
+            let package: Package = response.error_for_status()?.json().await?;
+
+            Ok(package
+                .community_listings
+                .into_iter()
+                .map(|x| x.community)
+                .collect())
+        }
+        .boxed()
+    }
+
+    fn package<'a>(
+        &'a self,
+        community: &'a str,
+        package: &'a TSPackage,
+    ) -> BoxFuture<'a, Result<Option<TSPackage>, UpstreamError>> {
+        async move {
+            // Lists saved before packages kept their id can only be refreshed whole
+            if package.uuid4.is_empty() {
+                return Err(UpstreamError::Unsupported);
+            }
+            let url = format!(
+                "{}/c/{community}/api/v1/package/{}/",
+                self.base_url, package.uuid4
+            );
+            let response = self.client.get(url).send().await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+
+            Ok(Some(response.error_for_status()?.json().await?))
+        }
+        .boxed()
+    }
+}
+
+impl Thunderstore {
+    // The body at `url`, or None if it's the same one `validators` were taken from
+    async fn get_if_changed(
+        &self,
+        url: String,
+        validators: Option<Validators>,
+    ) -> Result<(Option<Bytes>, Validators), UpstreamError> {
+        let mut request = self.client.get(url);
+        if let Some(validators) = &validators {
+            if let Some(etag) = &validators.etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = request.send().await?.error_for_status()?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok((None, validators.unwrap_or_default()));
+        }
+
+        let header_value = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let etag = header_value(header::ETAG);
+        let last_modified = header_value(header::LAST_MODIFIED);
+
+        let body = response.bytes().await?;
+        let hash = xxh3_64_with_seed(&body, BODY_HASH_SEED);
+
+        // Upstream doesn't always honor conditional requests, so compare the body too
+        let unchanged = validators.and_then(|v| v.hash) == Some(hash);
+        let validators = Validators {
+            etag,
+            last_modified,
+            hash: Some(hash),
+        };
+
+        Ok(((!unchanged).then_some(body), validators))
+    }
+}
+
+// The package index is gzipped JSON, one package version per line
+fn parse_index(body: &[u8]) -> Result<HashMap<String, u64>, UpstreamError> {
+    #[derive(Deserialize)]
+    struct Entry {
+        namespace: String,
+        name: String,
+        version_number: String,
+    }
+
+    // Served as a gzip file rather than gzip encoded, so reqwest leaves it compressed
+    let mut text = String::new();
+    if body.starts_with(&[0x1f, 0x8b]) {
+        GzDecoder::new(body)
+            .read_to_string(&mut text)
+            .map_err(UpstreamError::Index)?;
+    } else {
+        text = String::from_utf8_lossy(body).into_owned();
+    }
+
+    let mut versions: HashMap<String, Vec<String>> = HashMap::new();
+    for line in text.lines().filter(|x| !x.trim().is_empty()) {
+        let entry: Entry = serde_json::from_str(line)?;
+        versions
+            .entry(format!("{}-{}", entry.namespace, entry.name))
+            .or_default()
+            .push(entry.version_number);
+    }
+
+    Ok(versions
+        .into_iter()
+        .map(|(name, mut versions)| {
+            versions.sort_unstable();
+            let hash = xxh3_64_with_seed(versions.join("\n").as_bytes(), BODY_HASH_SEED);
+            (name, hash)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const INDEX: &str = r#"{"namespace":"Author","name":"CoolMod","version_number":"1.0.0"}
+{"namespace":"Author","name":"CoolMod","version_number":"1.1.0"}
+{"namespace":"Other-Team","name":"Lib","version_number":"2.0.0"}
+"#;
+
+    fn index(packages: HashMap<String, u64>) -> PackageIndex {
+        PackageIndex {
+            packages,
+            validators: Validators::default(),
+        }
+    }
+
+    #[test]
+    fn index_is_read_with_or_without_gzip() {
+        let mut gzipped = GzEncoder::new(vec![], Compression::default());
+        gzipped.write_all(INDEX.as_bytes()).unwrap();
+        let gzipped = parse_index(&gzipped.finish().unwrap()).unwrap();
+
+        let plain = parse_index(INDEX.as_bytes()).unwrap();
+        assert_eq!(gzipped, plain);
+        let mut names: Vec<_> = plain.keys().collect();
+        names.sort_unstable();
+        assert_eq!(names, ["Author-CoolMod", "Other-Team-Lib"]);
+    }
+
+    #[test]
+    fn version_order_does_not_change_the_hash() {
+        let reordered = INDEX.lines().rev().collect::<Vec<_>>().join("\n");
+        assert_eq!(
+            parse_index(INDEX.as_bytes()).unwrap(),
+            parse_index(reordered.as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn changes_cover_new_versions_and_removed_packages() {
+        let previous = index(parse_index(INDEX.as_bytes()).unwrap());
+        let current = index(
+            parse_index(
+                br#"{"namespace":"Author","name":"CoolMod","version_number":"1.0.0"}
+{"namespace":"Author","name":"CoolMod","version_number":"1.1.0"}
+{"namespace":"Author","name":"CoolMod","version_number":"1.2.0"}
+{"namespace":"Author","name":"NewMod","version_number":"1.0.0"}
+"#,
+            )
+            .unwrap(),
+        );
+
+        let (changed, added) = current.changes(&previous);
+        assert_eq!(changed, HashSet::from(["Author-CoolMod", "Other-Team-Lib"]));
+        assert_eq!(added, ["Author-NewMod"]);
+        assert_eq!(current.changes(&current), (HashSet::new(), vec![]));
+    }
+}
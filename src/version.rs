@@ -0,0 +1,222 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[error("`{0}` is not a valid NuGet version")]
+pub struct VersionError(String);
+
+// A NuGet version; equality and ordering ignore leading zeros, label case and build metadata
+#[derive(Clone, Debug)]
+pub struct Version {
+    // Major, minor, patch and revision, missing parts are 0
+    numbers: [u64; 4],
+    release: Vec<String>,
+    metadata: Option<String>,
+}
+
+impl Version {
+    pub fn is_prerelease(&self) -> bool {
+        !self.release.is_empty()
+    }
+
+    // Dotted labels and build metadata are only understood by SemVer 2.0.0 clients
+    pub fn is_semver2(&self) -> bool {
+        self.release.len() > 1 || self.metadata.is_some()
+    }
+}
+
+impl FromStr for Version {
+    type Err = VersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || VersionError(s.to_string());
+        let identifier = |x: &str, extra: &[u8]| {
+            !x.is_empty()
+                && x.bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || c == b'-' || extra.contains(&c))
+        };
+
+        let (rest, metadata) = match s.trim().split_once('+') {
+            Some((rest, metadata)) if identifier(metadata, b".") => {
+                (rest, Some(metadata.to_string()))
+            }
+            Some(_) => return Err(invalid()),
+            None => (s.trim(), None),
+        };
+        let (numbers, release) = match rest.split_once('-') {
+            Some((numbers, release)) => (numbers, Some(release)),
+            None => (rest, None),
+        };
+
+        let parts: Vec<&str> = numbers.split('.').collect();
+        if parts.len() > 4 {
+            return Err(invalid());
+        }
+        let mut parsed = [0; 4];
+        for (part, number) in parts.into_iter().zip(&mut parsed) {
+            if part.is_empty() || !part.bytes().all(|c| c.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            *number = part.parse().map_err(|_| invalid())?;
+        }
+
+        let release = match release {
+            Some(release) => {
+                let labels: Vec<String> = release.split('.').map(|x| x.to_string()).collect();
+                if !labels.iter().all(|x| identifier(x, b"")) {
+                    return Err(invalid());
+                }
+                labels
+            }
+            None => vec![],
+        };
+
+        Ok(Self {
+            numbers: parsed,
+            release,
+            metadata,
+        })
+    }
+}
+
+// The normalized form, which is what NuGet clients put in urls
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [major, minor, patch, revision] = self.numbers;
+        write!(f, "{major}.{minor}.{patch}")?;
+        if revision != 0 {
+            write!(f, ".{revision}")?;
+        }
+        if self.is_prerelease() {
+            write!(f, "-{}", self.release.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.numbers.cmp(&other.numbers).then_with(|| {
+            match (self.is_prerelease(), other.is_prerelease()) {
+                (false, false) => Ordering::Equal,
+                (false, true) => Ordering::Greater,
+                (true, false) => Ordering::Less,
+                (true, true) => self
+                    .release
+                    .iter()
+                    .zip(&other.release)
+                    .map(|(a, b)| compare_label(a, b))
+                    .find(|x| x.is_ne())
+                    .unwrap_or_else(|| self.release.len().cmp(&other.release.len())),
+            }
+        })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Version {}
+
+// Numeric labels sort numerically and before alphanumeric ones, like SemVer says
+fn compare_label(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a
+            .bytes()
+            .map(|c| c.to_ascii_lowercase())
+            .cmp(b.bytes().map(|c| c.to_ascii_lowercase())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(version: &str) -> String {
+        version.parse::<Version>().unwrap().to_string()
+    }
+
+    #[test]
+    fn versions_are_normalized() {
+        assert_eq!(normalize("1"), "1.0.0");
+        assert_eq!(normalize("01.002.3"), "1.2.3");
+        assert_eq!(normalize("1.2.3.0"), "1.2.3");
+        assert_eq!(normalize("1.2.3.4"), "1.2.3.4");
+        assert_eq!(normalize(" 1.0.0-Beta.1+abc "), "1.0.0-Beta.1");
+
+        for invalid in [
+            "",
+            "1.",
+            "1..2",
+            "a.b.c",
+            "1.2.3.4.5",
+            "1.0.0-",
+            "1.0.0-a..b",
+            "1.0+",
+        ] {
+            assert!(invalid.parse::<Version>().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn semver2_needs_dotted_labels_or_metadata() {
+        let version = |x: &str| x.parse::<Version>().unwrap();
+        assert!(!version("1.0.0").is_prerelease());
+        assert!(version("1.0.0-beta").is_prerelease());
+        assert!(!version("1.0.0-beta").is_semver2());
+        assert!(version("1.0.0-beta.1").is_semver2());
+        assert!(version("1.0.0+build").is_semver2());
+    }
+
+    #[test]
+    fn versions_are_ordered_like_nuget() {
+        let mut versions: Vec<Version> = [
+            "2.0.0",
+            "1.0.0",
+            "1.0.0-rc.1",
+            "1.0.0-beta.11",
+            "1.0.0-beta.2",
+            "1.0.0-beta",
+            "1.0.0-alpha",
+            "1.0.0-1",
+            "0.9.9.9",
+        ]
+        .iter()
+        .map(|x| x.parse().unwrap())
+        .collect();
+        versions.sort();
+
+        assert_eq!(
+            versions.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+            [
+                "0.9.9.9",
+                "1.0.0-1",
+                "1.0.0-alpha",
+                "1.0.0-beta",
+                "1.0.0-beta.2",
+                "1.0.0-beta.11",
+                "1.0.0-rc.1",
+                "1.0.0",
+                "2.0.0",
+            ]
+        );
+        assert_eq!(
+            "1.0.0-BETA+a".parse::<Version>().unwrap(),
+            "1.0.0.0-beta+b".parse::<Version>().unwrap()
+        );
+    }
+}
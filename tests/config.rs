@@ -0,0 +1,103 @@
+use clap::CommandFactory;
+use std::time::Duration;
+
+use ts_nuget::config::{Args, ConfigError, Settings};
+
+fn load(file: &str, flags: &[&str]) -> Result<Settings, ConfigError> {
+    load_with_env(file, flags, &[])
+}
+
+// Only reads the environment variables named in `vars`, so whatever else is set where the tests
+// run doesn't leak in
+fn load_with_env(file: &str, flags: &[&str], vars: &[&str]) -> Result<Settings, ConfigError> {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ts-nuget.toml");
+    std::fs::write(&path, file).unwrap();
+
+    let mut args = vec!["ts-nuget", "--config", path.to_str().unwrap()];
+    args.extend(flags);
+    let command = Args::command().mut_args(|arg| match arg.get_env() {
+        Some(var) if vars.iter().any(|x| var == *x) => arg,
+        _ => arg.env(None::<&str>),
+    });
+    let matches = command.try_get_matches_from(args).unwrap();
+    Settings::load(Args::from_matches(&matches).unwrap())
+}
+
+#[test]
+fn flags_override_the_config_file() {
+    let settings = load(
+        r#"
+        base_url = "http://localhost:5000"
+        bind = "127.0.0.1:5000"
+        refresh_interval = 60
+        communities = ["lethal-company"]
+
+        [conversion]
+        default_framework = "net472"
+        "#,
+        &["--port", "6000", "--communities", "riskofrain2, valheim"],
+    )
+    .unwrap();
+
+    assert_eq!(settings.bind, "127.0.0.1:6000".parse().unwrap());
+    assert_eq!(settings.server.base_url, "http://localhost:5000");
+    assert_eq!(settings.server.refresh_interval, Duration::from_secs(60));
+    assert_eq!(
+        settings.server.communities,
+        Some(["riskofrain2".to_string(), "valheim".to_string()].into())
+    );
+    assert_eq!(settings.server.conversion.default_framework, "net472");
+}
+
+#[test]
+fn port_from_the_environment_gives_way_to_a_bind_flag() {
+    // The only test setting a variable, and the others don't read any
+    std::env::set_var("NUGET_PORT", "6000");
+    let file = r#"
+        base_url = "http://localhost:5000"
+        bind = "127.0.0.1:5000"
+        "#;
+
+    let settings = load_with_env(file, &[], &["NUGET_PORT"]).unwrap();
+    assert_eq!(settings.bind, "127.0.0.1:6000".parse().unwrap());
+
+    let settings = load_with_env(file, &["--bind", "0.0.0.0:7000"], &["NUGET_PORT"]).unwrap();
+    assert_eq!(settings.bind, "0.0.0.0:7000".parse().unwrap());
+
+    let settings = load_with_env(
+        file,
+        &["--bind", "0.0.0.0:7000", "--port", "8000"],
+        &["NUGET_PORT"],
+    )
+    .unwrap();
+    assert_eq!(settings.bind, "0.0.0.0:8000".parse().unwrap());
+}
+
+#[test]
+fn invalid_settings_are_reported() {
+    assert!(matches!(load("", &[]), Err(ConfigError::MissingBaseUrl)));
+    assert!(matches!(
+        load(r#"base_url = "localhost:5000""#, &[]),
+        Err(ConfigError::InvalidUrl { .. })
+    ));
+    assert!(matches!(
+        load(
+            r#"base_url = "http://localhost""#,
+            &["--refresh-interval", "0"]
+        ),
+        Err(ConfigError::ZeroRefreshInterval)
+    ));
+    assert!(matches!(
+        load(r#"base_url = "http://localhost""#, &["--layout", "plugins"]),
+        Err(ConfigError::Layout(_))
+    ));
+    assert!(matches!(
+        load(
+            r#"base_url = "http://localhost"
+                port = 5000"#,
+            &[]
+        ),
+        Err(ConfigError::Parse { .. })
+    ));
+}
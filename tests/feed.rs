@@ -0,0 +1,738 @@
+use axum::body::Bytes;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::Json;
+use axum::routing::get;
+use axum::Router;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde_json::{json, Value};
+use std::io::{Cursor, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use zip::write::SimpleFileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use ts_nuget::layout::Layout;
+use ts_nuget::metadata::{Cache, PackageKey, TSPackage, TSVersion, Validators};
+use ts_nuget::upstream::{FetchedCommunity, FetchedIndex, Thunderstore, Upstream, UpstreamError};
+use ts_nuget::{AppState, ServerConfig};
+
+const COMMUNITY: &str = "test-community";
+const PDB_ID: &str = "131211101514171618191a1b1c1d1e1fffffffff";
+
+// A feed served on localhost from a fake Thunderstore, with its own data directory
+struct TestFeed {
+    base_url: String,
+    client: reqwest::Client,
+    data_dir: tempfile::TempDir,
+}
+
+impl TestFeed {
+    async fn start() -> Self {
+        Self::start_with(|_| ()).await
+    }
+
+    async fn start_with(configure: impl FnOnce(&mut ServerConfig)) -> Self {
+        let upstream = fake_thunderstore().await;
+        let data_dir = tempfile::tempdir().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let mut config = ServerConfig::new(&base_url);
+        config.upstream = Arc::new(Thunderstore::new(&upstream));
+        config.data_dir = data_dir.path().to_path_buf();
+        configure(&mut config);
+        let state = AppState::new(config).unwrap();
+        Cache::cache(&state.cache).await.unwrap();
+
+        let app = ts_nuget::router(state);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        Self {
+            base_url,
+            client: reqwest::Client::new(),
+            data_dir,
+        }
+    }
+
+    async fn download(&self, version: &str) -> Bytes {
+        let response = self
+            .get(&format!(
+                "/nuget/v3/base/author-coolmod/{version}/author-coolmod.{version}.nupkg"
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        response.bytes().await.unwrap()
+    }
+
+    // Eviction also happens when a download finishes streaming, so give the server a moment
+    async fn wait_for_nupkgs(&self, expected: &[&str]) {
+        let mut names = vec![];
+        for _ in 0..50 {
+            names = std::fs::read_dir(self.data_dir.path().join("nupkgs"))
+                .unwrap()
+                .map(|x| x.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            names.sort_unstable();
+            if names == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(names, expected);
+    }
+
+    async fn get(&self, path: &str) -> reqwest::Response {
+        self.client
+            .get(format!("{}{path}", self.base_url))
+            .send()
+            .await
+            .unwrap()
+    }
+
+    async fn json(&self, path: &str) -> Value {
+        let response = self.get(path).await;
+        assert_eq!(response.status(), StatusCode::OK, "GET {path}");
+        response.json().await.unwrap()
+    }
+}
+
+async fn fake_thunderstore() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let packages = packages(&url);
+
+    let app = Router::new()
+        .route(
+            "/api/experimental/community/",
+            get(|| async {
+                Json(json!({
+                    "pagination": { "next_link": null },
+                    "results": [{ "identifier": COMMUNITY }],
+                }))
+            }),
+        )
+        .route(
+            "/c/{community}/api/v1/package/",
+            get(move |Path(community): Path<String>| async move {
+                match community.as_str() {
+                    COMMUNITY => Ok(Json(packages)),
+                    _ => Err(StatusCode::NOT_FOUND),
+                }
+            }),
+        )
+        .route(
+            "/package/download/Author/CoolMod/{version}/",
+            get(|| async { mod_archive() }),
+        )
+        .route(
+            "/package/download/Author/BrokenMod/{version}/",
+            get(|| async { corrupt_archive() }),
+        );
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    url
+}
+
+fn version(upstream: &str, name: &str, version: &str, dependencies: &[&str]) -> Value {
+    json!({
+        "description": format!("{name} {version}"),
+        "icon": format!("{upstream}/icons/{name}.png"),
+        "version_number": version,
+        "download_url": format!("{upstream}/package/download/Author/{name}/{version}/"),
+        "downloads": 10,
+        "date_created": "2024-01-01T00:00:00Z",
+        "website_url": "",
+        "dependencies": dependencies,
+    })
+}
+
+// Thunderstore lists the newest upload first
+fn packages(upstream: &str) -> Value {
+    json!([
+        {
+            "full_name": "Author-CoolMod",
+            "package_url": format!("{upstream}/package/Author/CoolMod/"),
+            "is_deprecated": false,
+            "categories": ["Mods"],
+            "versions": [
+                version(upstream, "CoolMod", "1.1.0", &["Author-CoolLib-2.0.0"]),
+                version(upstream, "CoolMod", "1.0.0", &[]),
+            ],
+        },
+        {
+            "full_name": "Author-BrokenMod",
+            "package_url": format!("{upstream}/package/Author/BrokenMod/"),
+            "is_deprecated": false,
+            "categories": ["Mods"],
+            "versions": [version(upstream, "BrokenMod", "1.0.0", &[])],
+        },
+        {
+            "full_name": "Author-CoolLib",
+            "package_url": format!("{upstream}/package/Author/CoolLib/"),
+            "is_deprecated": false,
+            "categories": ["Libraries"],
+            "versions": [version(upstream, "CoolLib", "2.0.0", &[])],
+        },
+    ])
+}
+
+fn mod_archive() -> Vec<u8> {
+    let mut zip = ZipWriter::new(Cursor::new(vec![]));
+    for (name, contents) in [
+        (
+            "BepInEx/plugins/CoolMod/CoolMod.dll",
+            &b"not really an assembly"[..],
+        ),
+        ("BepInEx/plugins/CoolMod/CoolMod.xml", b"<doc />"),
+        ("BepInEx/patchers/CoolPatcher.dll", b"patcher"),
+        ("BepInEx/patchers/CoolPatcher.pdb", &portable_pdb()),
+        ("README.md", b"# CoolMod"),
+    ] {
+        zip.start_file(name, SimpleFileOptions::default()).unwrap();
+        zip.write_all(contents).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+// A metadata root with just a `#Pdb` stream, whose id is PDB_ID
+fn portable_pdb() -> Vec<u8> {
+    let mut pdb = vec![];
+    pdb.extend(0x424A_5342u32.to_le_bytes());
+    pdb.extend([1, 0, 1, 0, 0, 0, 0, 0]);
+    pdb.extend(12u32.to_le_bytes());
+    pdb.extend(b"PDB v1.0\0\0\0\0");
+    pdb.extend([0, 0, 1, 0]);
+    pdb.extend(48u32.to_le_bytes());
+    pdb.extend(20u32.to_le_bytes());
+    pdb.extend(b"#Pdb\0\0\0\0");
+    pdb.extend(0x10..0x20);
+    pdb.extend([0; 4]);
+    pdb
+}
+
+// Intact zip structure, but the plugin's compressed data is damaged
+fn corrupt_archive() -> Vec<u8> {
+    let mut zip = ZipWriter::new(Cursor::new(vec![]));
+    zip.start_file(
+        "BepInEx/plugins/BrokenMod.dll",
+        SimpleFileOptions::default(),
+    )
+    .unwrap();
+    zip.write_all(&[0x5a; 4096]).unwrap();
+    let mut bytes = zip.finish().unwrap().into_inner();
+
+    let data = 30 + "BepInEx/plugins/BrokenMod.dll".len();
+    for byte in &mut bytes[data..data + 4] {
+        *byte ^= 0xff;
+    }
+    bytes
+}
+
+#[tokio::test]
+async fn service_index_points_at_the_feed() {
+    let feed = TestFeed::start().await;
+
+    let index = feed.json("/nuget/v3/index.json").await;
+    let resources = index["resources"].as_array().unwrap();
+    let resource = |res_type: &str| {
+        resources
+            .iter()
+            .find(|x| x["@type"] == res_type)
+            .map(|x| x["@id"].as_str().unwrap().to_string())
+    };
+    let base_url = &feed.base_url;
+    assert_eq!(
+        resource("SearchQueryService"),
+        Some(format!("{base_url}/nuget/v3/search"))
+    );
+    assert_eq!(
+        resource("RegistrationsBaseUrl"),
+        Some(format!("{base_url}/nuget/v3/package"))
+    );
+    assert_eq!(
+        resource("PackageBaseAddress/3.0.0"),
+        Some(format!("{base_url}/nuget/v3/base"))
+    );
+
+    let index = feed
+        .json(&format!("/c/{COMMUNITY}/nuget/v3/index.json"))
+        .await;
+    assert!(index["resources"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|x| x["@id"]
+            .as_str()
+            .unwrap()
+            .starts_with(&format!("{base_url}/c/{COMMUNITY}/"))));
+
+    let response = feed.get("/c/missing/nuget/v3/index.json").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn search_finds_packages() {
+    let feed = TestFeed::start().await;
+
+    let results = feed.json("/nuget/v3/search?q=cool").await;
+    assert_eq!(results["totalHits"], 2);
+
+    let results = feed
+        .json("/nuget/v3/search?q=packageid:Author-CoolMod")
+        .await;
+    assert_eq!(results["totalHits"], 1);
+    let item = &results["data"][0];
+    assert_eq!(item["id"], "Author-CoolMod");
+    assert_eq!(item["version"], "1.1.0");
+    assert!(item["description"]
+        .as_str()
+        .unwrap()
+        .starts_with("CoolMod 1.1.0"));
+    assert_eq!(
+        item["versions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["version"].as_str().unwrap())
+            .collect::<Vec<_>>(),
+        ["1.0.0", "1.1.0"]
+    );
+    assert_eq!(item["totalDownloads"], 20);
+
+    assert_eq!(item["packageTypes"], json!([]));
+}
+
+#[tokio::test]
+async fn package_types_come_from_converted_archives() {
+    let feed = TestFeed::start().await;
+
+    // Nothing has been converted, so no package has a type yet
+    let results = feed.json("/nuget/v3/search?packageType=Dependency").await;
+    assert_eq!(results["totalHits"], 0);
+
+    feed.download("1.1.0").await;
+    let results = feed.json("/nuget/v3/search?packageType=Dependency").await;
+    assert_eq!(results["totalHits"], 1);
+    assert_eq!(results["data"][0]["id"], "Author-CoolMod");
+    assert_eq!(
+        results["data"][0]["packageTypes"],
+        json!([{ "name": "Dependency" }])
+    );
+    let results = feed.json("/nuget/v3/search?packageType=Plugin").await;
+    assert_eq!(results["totalHits"], 0);
+
+    let feed = TestFeed::start_with(|config| {
+        config.conversion.layout = Layout::parse("**/*.dll=content").unwrap();
+    })
+    .await;
+    feed.download("1.1.0").await;
+    let results = feed.json("/nuget/v3/search?packageType=plugin").await;
+    assert_eq!(results["totalHits"], 1);
+    assert_eq!(
+        results["data"][0]["packageTypes"],
+        json!([{ "name": "Plugin" }])
+    );
+    let results = feed
+        .json("/nuget/v3/autocomplete?packageType=Dependency")
+        .await;
+    assert_eq!(results["totalHits"], 0);
+}
+
+#[tokio::test]
+async fn registration_lists_versions_and_dependencies() {
+    let feed = TestFeed::start().await;
+
+    let index = feed
+        .json("/nuget/v3/package/author-coolmod/index.json")
+        .await;
+    assert_eq!(index["count"], 1);
+    let page = &index["items"][0];
+    assert_eq!(page["lower"], "1.0.0");
+    assert_eq!(page["upper"], "1.1.0");
+    assert_eq!(page["count"], 2);
+
+    let latest = &page["items"][1];
+    assert_eq!(
+        latest["@id"],
+        format!(
+            "{}/nuget/v3/package/author-coolmod/1.1.0.json",
+            feed.base_url
+        )
+    );
+    let dependency = &latest["catalogEntry"]["dependencyGroups"][0]["dependencies"][0];
+    assert_eq!(dependency["id"], "Author-CoolLib");
+    assert_eq!(dependency["range"], "[2.0.0,)");
+
+    let leaf = feed
+        .json("/nuget/v3/package/author-coolmod/1.1.0.json")
+        .await;
+    assert_eq!(leaf["catalogEntry"]["version"], "1.1.0");
+
+    let response = feed
+        .get("/nuget/v3/package/author-missing/index.json")
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn community_feed_links_stay_in_the_community() {
+    let feed = TestFeed::start().await;
+    let scope = format!("{}/c/{COMMUNITY}/nuget/v3/", feed.base_url);
+    let fetch = |url: &Value| {
+        let url = url.as_str().unwrap().to_string();
+        assert!(url.starts_with(&scope), "{url} isn't under {scope}");
+        let client = feed.client.clone();
+        async move {
+            let response = client.get(&url).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "GET {url}");
+            response
+        }
+    };
+
+    let results = feed
+        .json(&format!(
+            "/c/{COMMUNITY}/nuget/v3/search?q=packageid:Author-CoolMod"
+        ))
+        .await;
+    let item = &results["data"][0];
+    assert_eq!(item["id"], "Author-CoolMod");
+
+    let index: Value = fetch(&item["registration"]).await.json().await.unwrap();
+    let page = &index["items"][0];
+    assert!(page["@id"].as_str().unwrap().starts_with(&scope));
+    let latest = &page["items"][1];
+    assert_eq!(latest["catalogEntry"]["version"], "1.1.0");
+
+    let leaf: Value = fetch(&latest["@id"]).await.json().await.unwrap();
+    assert!(leaf["registration"].as_str().unwrap().starts_with(&scope));
+    let bytes = fetch(&leaf["packageContent"]).await.bytes().await.unwrap();
+    ZipArchive::new(Cursor::new(bytes)).unwrap();
+}
+
+#[tokio::test]
+async fn flat_container_lists_versions() {
+    let feed = TestFeed::start().await;
+
+    let versions = feed.json("/nuget/v3/base/author-coolmod/index.json").await;
+    assert_eq!(versions, json!({ "versions": ["1.0.0", "1.1.0"] }));
+
+    let response = feed.get("/nuget/v3/base/author-missing/index.json").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn nupkg_contains_converted_archive() {
+    let feed = TestFeed::start().await;
+
+    let bytes = feed.download("1.1.0").await;
+
+    let mut nupkg = ZipArchive::new(Cursor::new(bytes)).unwrap();
+    let mut names: Vec<_> = nupkg.file_names().collect();
+    names.sort_unstable();
+    assert_eq!(
+        names,
+        [
+            "Author-CoolMod.nuspec",
+            "lib/netstandard2.0/CoolMod/CoolMod.dll",
+            "lib/netstandard2.0/CoolMod/CoolMod.xml",
+        ]
+    );
+
+    let mut nuspec = String::new();
+    nupkg
+        .by_name("Author-CoolMod.nuspec")
+        .unwrap()
+        .read_to_string(&mut nuspec)
+        .unwrap();
+    assert!(nuspec.contains("<id>Author-CoolMod</id>"));
+    assert!(nuspec.contains("<version>1.1.0</version>"));
+    assert!(nuspec.contains("<dependency id=\"Author-CoolLib\" version=\"[2.0.0,)\" />"));
+}
+
+#[tokio::test]
+async fn layout_places_every_archive_file() {
+    let feed = TestFeed::start_with(|config| {
+        config.conversion.layout = Layout::parse(
+            "README.md=content/docs;**/*.pdb=content/symbols;**/patchers/**=skip;**/plugins/**=lib",
+        )
+        .unwrap();
+    })
+    .await;
+
+    let bytes = feed.download("1.1.0").await;
+
+    // The patcher's symbols stay with the skipped patcher, whatever the rules say about them
+    let nupkg = ZipArchive::new(Cursor::new(bytes)).unwrap();
+    let mut names: Vec<_> = nupkg.file_names().collect();
+    names.sort_unstable();
+    assert_eq!(
+        names,
+        [
+            "Author-CoolMod.nuspec",
+            "content/docs/README.md",
+            "lib/netstandard2.0/CoolMod/CoolMod.dll",
+            "lib/netstandard2.0/CoolMod/CoolMod.xml",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn symbols_are_indexed_when_the_archive_is_fetched() {
+    let feed = TestFeed::start().await;
+
+    let index = feed.json("/nuget/v3/index.json").await;
+    assert!(index["resources"].as_array().unwrap().iter().any(|x| {
+        x["@type"] == "SymbolServer/SSQP" && x["@id"] == format!("{}/symbols", feed.base_url)
+    }));
+
+    let path = format!("/symbols/CoolPatcher.pdb/{PDB_ID}/coolpatcher.pdb");
+    assert_eq!(feed.get(&path).await.status(), StatusCode::NOT_FOUND);
+
+    // The patcher is skipped by the layout, but its symbols are still indexed
+    feed.download("1.1.0").await;
+    let response = feed.get(&path).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.bytes().await.unwrap(), portable_pdb());
+}
+
+#[tokio::test]
+async fn upstream_download_failure_is_a_bad_gateway() {
+    let feed = TestFeed::start().await;
+
+    // The fake Thunderstore only serves CoolMod archives
+    let response = feed
+        .get("/nuget/v3/base/author-coollib/2.0.0/author-coollib.2.0.0.nupkg")
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn least_recently_used_nupkgs_are_evicted() {
+    let sizes = {
+        let feed = TestFeed::start().await;
+        [
+            feed.download("1.0.0").await.len(),
+            feed.download("1.1.0").await.len(),
+        ]
+    };
+
+    // Room for either package, but not both. Sizes vary a little with the upstream's port
+    let budget = sizes[0].max(sizes[1]) + sizes[0].min(sizes[1]) / 2;
+    let feed = TestFeed::start_with(|config| config.nupkg_cache_size = Some(budget as u64)).await;
+
+    feed.download("1.0.0").await;
+    feed.wait_for_nupkgs(&["Author-CoolMod.1.0.0.nupkg"]).await;
+    feed.download("1.1.0").await;
+    feed.wait_for_nupkgs(&["Author-CoolMod.1.1.0.nupkg"]).await;
+
+    // Evicted packages are converted again when they're next requested
+    feed.download("1.0.0").await;
+    feed.wait_for_nupkgs(&["Author-CoolMod.1.0.0.nupkg"]).await;
+}
+
+#[tokio::test]
+async fn nupkgs_over_budget_are_kept_while_streaming() {
+    let feed = TestFeed::start_with(|config| config.nupkg_cache_size = Some(1)).await;
+
+    let bytes = feed.download("1.1.0").await;
+    assert!(ZipArchive::new(Cursor::new(bytes)).is_ok());
+    feed.wait_for_nupkgs(&[]).await;
+
+    let status = feed.json("/status").await;
+    assert_eq!(status["nupkg_cache_size"], 0);
+}
+
+#[tokio::test]
+async fn corrupt_archive_is_a_bad_gateway() {
+    let feed = TestFeed::start().await;
+
+    let response = feed
+        .get("/nuget/v3/base/author-brokenmod/1.0.0/author-brokenmod.1.0.0.nupkg")
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+// Claims every package list is unchanged, even ones it never sent
+struct AlwaysUnchanged;
+
+impl Upstream for AlwaysUnchanged {
+    fn communities(&self) -> BoxFuture<'_, Result<Vec<String>, UpstreamError>> {
+        async { Ok(vec![COMMUNITY.to_string()]) }.boxed()
+    }
+
+    fn packages<'a>(
+        &'a self,
+        _community: &'a str,
+        validators: Option<Validators>,
+    ) -> BoxFuture<'a, Result<FetchedCommunity, UpstreamError>> {
+        async move {
+            Ok(FetchedCommunity {
+                packages: None,
+                validators: validators.unwrap_or_default(),
+            })
+        }
+        .boxed()
+    }
+
+    fn download<'a>(&'a self, _url: &'a str) -> BoxFuture<'a, Result<Bytes, UpstreamError>> {
+        async { Err(UpstreamError::UnknownUnchanged) }.boxed()
+    }
+}
+
+#[tokio::test]
+async fn unchanged_list_without_a_previous_one_is_a_failure() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let mut config = ServerConfig::new("http://localhost");
+    config.upstream = Arc::new(AlwaysUnchanged);
+    config.data_dir = data_dir.path().to_path_buf();
+    let state = AppState::new(config).unwrap();
+    Cache::cache(&state.cache).await.unwrap();
+
+    let cache = state.cache.read().await;
+    let status = &cache.communities[COMMUNITY];
+    assert!(status.last_success.is_none());
+    assert!(status.last_error.is_some());
+    assert!(cache.feed(Some(COMMUNITY)).is_none());
+}
+
+// Serves a package index next to a list that tests change between refreshes
+struct Indexed {
+    packages: Mutex<Vec<TSPackage>>,
+    whole_lists: AtomicUsize,
+}
+
+impl Indexed {
+    fn new() -> Self {
+        let mut packages: Vec<TSPackage> =
+            serde_json::from_value(packages("http://upstream")).unwrap();
+        for (i, pkg) in packages.iter_mut().enumerate() {
+            pkg.uuid4 = format!("uuid-{i}");
+        }
+        Self {
+            packages: Mutex::new(packages),
+            whole_lists: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Upstream for Indexed {
+    fn communities(&self) -> BoxFuture<'_, Result<Vec<String>, UpstreamError>> {
+        async { Ok(vec![COMMUNITY.to_string()]) }.boxed()
+    }
+
+    fn packages<'a>(
+        &'a self,
+        _community: &'a str,
+        _validators: Option<Validators>,
+    ) -> BoxFuture<'a, Result<FetchedCommunity, UpstreamError>> {
+        async move {
+            self.whole_lists.fetch_add(1, Ordering::SeqCst);
+            Ok(FetchedCommunity {
+                packages: Some(self.packages.lock().unwrap().clone()),
+                validators: Validators::default(),
+            })
+        }
+        .boxed()
+    }
+
+    fn download<'a>(&'a self, _url: &'a str) -> BoxFuture<'a, Result<Bytes, UpstreamError>> {
+        async { Err(UpstreamError::Unsupported) }.boxed()
+    }
+
+    fn package_index(
+        &self,
+        _validators: Option<Validators>,
+    ) -> BoxFuture<'_, Result<Option<FetchedIndex>, UpstreamError>> {
+        async move {
+            // Enough to tell when versions come and go
+            let packages = self
+                .packages
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|x| (x.full_name.clone(), x.versions.len() as u64))
+                .collect();
+            Ok(Some(FetchedIndex {
+                packages: Some(packages),
+                validators: Validators::default(),
+            }))
+        }
+        .boxed()
+    }
+
+    fn listings<'a>(
+        &'a self,
+        _full_name: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<String>, UpstreamError>> {
+        async { Ok(vec![COMMUNITY.to_string()]) }.boxed()
+    }
+
+    fn package<'a>(
+        &'a self,
+        _community: &'a str,
+        package: &'a TSPackage,
+    ) -> BoxFuture<'a, Result<Option<TSPackage>, UpstreamError>> {
+        async move {
+            let packages = self.packages.lock().unwrap();
+            Ok(packages.iter().find(|x| x.uuid4 == package.uuid4).cloned())
+        }
+        .boxed()
+    }
+}
+
+async fn versions(cache: &tokio::sync::RwLock<Cache>, name: &'static str) -> Option<usize> {
+    let cache = cache.read().await;
+    let feed = cache.feed(Some(COMMUNITY)).unwrap();
+    let pkg = feed.package(&PackageKey::try_from(name).unwrap());
+    pkg.map(|x| x.versions.len())
+}
+
+#[tokio::test]
+async fn package_index_changes_only_refetch_changed_packages() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let upstream = Arc::new(Indexed::new());
+    let mut config = ServerConfig::new("http://localhost");
+    config.upstream = upstream.clone();
+    config.data_dir = data_dir.path().to_path_buf();
+    let state = AppState::new(config).unwrap();
+
+    Cache::cache(&state.cache).await.unwrap();
+    assert_eq!(upstream.whole_lists.load(Ordering::SeqCst), 1);
+
+    // A new version and a removed package are fetched from the index alone
+    {
+        let mut packages = upstream.packages.lock().unwrap();
+        let version = packages[0].versions[0].clone();
+        packages[0].versions.insert(
+            0,
+            TSVersion {
+                version_number: "1.2.0".to_string(),
+                ..version
+            },
+        );
+        packages.retain(|x| x.full_name != "Author-BrokenMod");
+    }
+    Cache::cache(&state.cache).await.unwrap();
+    assert_eq!(upstream.whole_lists.load(Ordering::SeqCst), 1);
+    assert_eq!(versions(&state.cache, "Author-CoolMod").await, Some(3));
+    assert_eq!(versions(&state.cache, "Author-BrokenMod").await, None);
+
+    Cache::cache(&state.cache).await.unwrap();
+    assert_eq!(upstream.whole_lists.load(Ordering::SeqCst), 1);
+
+    // Only the whole list says where a new package goes
+    {
+        let mut packages = upstream.packages.lock().unwrap();
+        let mut pkg = packages[0].clone();
+        pkg.full_name = "Author-NewMod".to_string();
+        pkg.uuid4 = "uuid-new".to_string();
+        packages.push(pkg);
+    }
+    Cache::cache(&state.cache).await.unwrap();
+    assert_eq!(upstream.whole_lists.load(Ordering::SeqCst), 2);
+    assert_eq!(versions(&state.cache, "Author-NewMod").await, Some(3));
+}